@@ -0,0 +1,44 @@
+use std::collections::BTreeMap;
+
+use chrono::{TimeZone, Utc};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use victoria_metrics_writer::MetricsWriter;
+
+fn bench_add(c: &mut Criterion) {
+    let labels = BTreeMap::from([("job", "node_exporter"), ("instance", "localhost:9100")]);
+    let values: Vec<u64> = (0..10).collect();
+    let timestamps: Vec<_> = (0..10)
+        .map(|i| {
+            Utc.timestamp_millis_opt(1549891472010 + i * 15_000)
+                .unwrap()
+        })
+        .collect();
+    let timestamps_ms: Vec<i64> = timestamps.iter().map(|ts| ts.timestamp_millis()).collect();
+
+    // Every iteration adds to a fresh writer, created outside the timing.
+    c.bench_function("add", |b| {
+        b.iter_batched_ref(
+            MetricsWriter::localhost,
+            |writer| {
+                writer
+                    .add(black_box("up"), &labels, &values, &timestamps)
+                    .unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function("add_millis", |b| {
+        b.iter_batched_ref(
+            MetricsWriter::localhost,
+            |writer| {
+                writer
+                    .add_millis(black_box("up"), &labels, &values, &timestamps_ms)
+                    .unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_add);
+criterion_main!(benches);
@@ -0,0 +1,298 @@
+use std::{mem, ops::Range};
+
+use crate::BufferedMetric;
+
+/// The JSON lines buffered for one import target, split into batches that
+/// are each sent in a request of their own. Batches are taken out together
+/// with the number of samples they hold.
+#[derive(Debug, Default)]
+pub(crate) struct Buffer {
+    bytes: Vec<u8>,
+    /// Where each batch but the last ends in `bytes`, and its samples.
+    sealed: Vec<(usize, usize)>,
+    /// The samples in the last batch.
+    samples: usize,
+    /// Where the first line not yet returned by [`Buffer::next_line`]
+    /// starts.
+    line_start: usize,
+    /// The number of line breaks in `bytes`.
+    lines: usize,
+}
+
+impl Buffer {
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// The number of line breaks buffered, kept up to date as lines are
+    /// added and taken out rather than counted on every call.
+    pub(crate) fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /// The lines of the last batch, the one new lines are added to.
+    pub(crate) fn open_batch(&self) -> &[u8] {
+        &self.bytes[self.open_batch_start()..]
+    }
+
+    fn open_batch_start(&self) -> usize {
+        self.sealed.last().map_or(0, |(end, _)| *end)
+    }
+
+    pub(crate) fn has_sealed(&self) -> bool {
+        !self.sealed.is_empty()
+    }
+
+    /// Appends a line with `samples` samples written by `write`, adding the
+    /// line break.
+    pub(crate) fn write_line(&mut self, samples: usize, write: impl FnOnce(&mut Vec<u8>)) {
+        write(&mut self.bytes);
+        self.bytes.extend_from_slice(b"\r\n");
+        self.line_start = self.bytes.len();
+        self.lines += 1;
+        self.samples += samples;
+    }
+
+    /// Counts `samples` more samples in the open batch, for lines appended
+    /// with [`Buffer::append`].
+    pub(crate) fn add_samples(&mut self, samples: usize) {
+        self.samples += samples;
+    }
+
+    /// Appends raw bytes, which may end in the middle of a line.
+    pub(crate) fn append(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+        self.lines += count_lines(bytes);
+    }
+
+    /// Returns the range of the next complete line appended with
+    /// [`Buffer::append`], including its line break.
+    pub(crate) fn next_line(&mut self) -> Option<Range<usize>> {
+        let start = self.line_start;
+        let end = start + self.bytes[start..].iter().position(|b| *b == b'\n')? + 1;
+        self.line_start = end;
+        Some(start..end)
+    }
+
+    /// Ends the open batch at `offset`, the start of a line, so that the
+    /// lines from there on go into a new one.
+    pub(crate) fn seal_at(&mut self, offset: usize) {
+        if offset > self.open_batch_start() {
+            self.sealed.push((offset, mem::take(&mut self.samples)));
+        }
+    }
+
+    /// Remembers the current end of the buffer for [`Buffer::rollback`].
+    pub(crate) fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            len: self.bytes.len(),
+            sealed: self.sealed.len(),
+            samples: self.samples,
+            line_start: self.line_start,
+            lines: self.lines,
+        }
+    }
+
+    /// Removes everything added since `checkpoint` was taken, provided
+    /// nothing was taken out in between.
+    pub(crate) fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.bytes.truncate(checkpoint.len);
+        self.sealed.truncate(checkpoint.sealed);
+        self.samples = checkpoint.samples;
+        self.line_start = checkpoint.line_start;
+        self.lines = checkpoint.lines;
+    }
+
+    /// Removes all batches but the open one.
+    pub(crate) fn take_sealed(&mut self) -> Vec<(Vec<u8>, usize)> {
+        let end = match self.sealed.last() {
+            Some((end, _)) => *end,
+            None => return Vec::new(),
+        };
+        let rest = self.bytes.split_off(end);
+        let bytes = mem::replace(&mut self.bytes, rest);
+        self.line_start -= end;
+        // Only the open batch is left, which is rarely more than a few lines.
+        self.lines = count_lines(&self.bytes);
+        split(bytes, mem::take(&mut self.sealed))
+    }
+
+    /// Removes all batches, leaving the buffer empty.
+    pub(crate) fn take_batches(&mut self) -> Vec<(Vec<u8>, usize)> {
+        let Buffer {
+            bytes,
+            mut sealed,
+            samples,
+            ..
+        } = mem::take(self);
+        if bytes.is_empty() {
+            return Vec::new();
+        }
+        sealed.push((bytes.len(), samples));
+        split(bytes, sealed)
+    }
+
+    /// Removes the first `end` bytes, which must end at a line break,
+    /// dropping the batches they cover entirely. With `recount`, the samples
+    /// of the batch cut in two are counted again by parsing its drained
+    /// lines; otherwise its count is left as is.
+    pub(crate) fn drain_front(&mut self, end: usize, recount: bool) -> Vec<u8> {
+        let len = self.bytes.len();
+        let rest = self.bytes.split_off(end);
+        let drained = mem::replace(&mut self.bytes, rest);
+        self.lines -= count_lines(&drained);
+        let mut start = 0;
+        let mut cut = |batch_end: usize, samples: &mut usize| {
+            if end >= batch_end {
+                *samples = 0;
+            } else if start < end && recount {
+                *samples = samples.saturating_sub(count_samples(&drained[start..]));
+            }
+            start = batch_end;
+        };
+        for (batch_end, samples) in &mut self.sealed {
+            cut(*batch_end, samples);
+        }
+        cut(len, &mut self.samples);
+        self.sealed.retain_mut(|(batch_end, _)| {
+            *batch_end = batch_end.saturating_sub(end);
+            *batch_end > 0
+        });
+        self.line_start = self.line_start.saturating_sub(end);
+        drained
+    }
+}
+
+/// The state of a [`Buffer`] at some point, see [`Buffer::checkpoint`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Checkpoint {
+    len: usize,
+    sealed: usize,
+    samples: usize,
+    line_start: usize,
+    lines: usize,
+}
+
+fn count_lines(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|b| **b == b'\n').count()
+}
+
+/// Counts the samples in `lines`, parsing them back out.
+pub(crate) fn count_samples(lines: &[u8]) -> usize {
+    lines
+        .split(|b| *b == b'\n')
+        .filter_map(|line| serde_json::from_slice::<BufferedMetric>(line).ok())
+        .map(|metric| metric.values.len())
+        .sum()
+}
+
+/// Splits `bytes` at the batch ends in `sealed`, the last of which is the
+/// end of `bytes`.
+fn split(mut bytes: Vec<u8>, sealed: Vec<(usize, usize)>) -> Vec<(Vec<u8>, usize)> {
+    let mut batches: Vec<(Vec<u8>, usize)> = sealed
+        .windows(2)
+        .rev()
+        .map(|pair| (bytes.split_off(pair[0].0), pair[1].1))
+        .collect();
+    batches.push((bytes, sealed[0].1));
+    batches.reverse();
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batches() {
+        let line = |job: &str, samples: usize| {
+            format!(
+                r#"{{"metric":{{"__name__":"up","job":"{}"}},"values":{:?},"timestamps":{:?}}}"#,
+                job,
+                vec![1; samples],
+                vec![1; samples]
+            )
+        };
+        let mut buffer = Buffer::default();
+        for (job, samples) in [("a", 1), ("b", 2), ("c", 3), ("d", 4)] {
+            if job == "c" || job == "d" {
+                buffer.seal_at(buffer.len());
+            }
+            buffer.write_line(samples, |bytes| {
+                bytes.extend_from_slice(line(job, samples).as_bytes())
+            });
+        }
+        assert_eq!(
+            buffer.open_batch(),
+            format!("{}\r\n", line("d", 4)).as_bytes()
+        );
+
+        let first = line("a", 1).len() + 2;
+        assert_eq!(
+            buffer.drain_front(first, true),
+            format!("{}\r\n", line("a", 1)).as_bytes()
+        );
+        assert_eq!(
+            buffer.take_sealed(),
+            [
+                (format!("{}\r\n", line("b", 2)).into_bytes(), 2),
+                (format!("{}\r\n", line("c", 3)).into_bytes(), 3)
+            ]
+        );
+        assert!(!buffer.has_sealed());
+        buffer.seal_at(buffer.len());
+        buffer.write_line(5, |bytes| bytes.extend_from_slice(line("e", 5).as_bytes()));
+        assert_eq!(
+            buffer.take_batches(),
+            [
+                (format!("{}\r\n", line("d", 4)).into_bytes(), 4),
+                (format!("{}\r\n", line("e", 5)).into_bytes(), 5)
+            ]
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_drain_front_without_recount() {
+        let mut buffer = Buffer::default();
+        buffer.write_line(1, |bytes| bytes.extend_from_slice(b"a"));
+        buffer.write_line(2, |bytes| bytes.extend_from_slice(b"b"));
+        // Neither drain parses the lines, the second clears the count.
+        buffer.drain_front(3, false);
+        buffer.drain_front(3, false);
+        buffer.write_line(4, |bytes| bytes.extend_from_slice(b"c"));
+        assert_eq!(buffer.take_batches(), [(b"c\r\n".to_vec(), 4)]);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut buffer = Buffer::default();
+        buffer.write_line(1, |bytes| bytes.extend_from_slice(b"a"));
+        let checkpoint = buffer.checkpoint();
+        buffer.seal_at(buffer.len());
+        buffer.write_line(2, |bytes| bytes.extend_from_slice(b"b"));
+        buffer.rollback(checkpoint);
+        assert!(!buffer.has_sealed());
+        assert_eq!(buffer.lines(), 1);
+        assert_eq!(buffer.take_batches(), [(b"a\r\n".to_vec(), 1)]);
+    }
+
+    #[test]
+    fn test_next_line() {
+        let mut buffer = Buffer::default();
+        buffer.append(b"a\nb");
+        assert_eq!(buffer.next_line(), Some(0..2));
+        assert_eq!(buffer.next_line(), None);
+        buffer.append(b"c\n");
+        assert_eq!(buffer.next_line(), Some(2..5));
+        assert_eq!(buffer.lines(), 2);
+    }
+}
@@ -0,0 +1,39 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::AsyncWrite;
+
+use crate::MetricsWriter;
+
+/// An [`AsyncWrite`] handle appending raw bytes to a writer's buffer, see
+/// [`MetricsWriter::buffer_writer`](crate::MetricsWriter::buffer_writer).
+///
+/// Bytes are appended as-is. Keeping the buffer made of complete JSON lines
+/// in the import format is the caller's responsibility, and none of the
+/// writer's `add` checks apply. Completed lines do count towards
+/// [`MetricsWriter::with_max_series_per_flush`].
+pub struct BufferWriter<'a> {
+    pub(crate) writer: &'a mut MetricsWriter,
+}
+
+impl AsyncWrite for BufferWriter<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().writer.write_raw(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Opens after `threshold` consecutive failed requests and stays open for
+/// `cooldown`. Once the cooldown has passed, the next request is let through
+/// and either closes the circuit again or reopens it.
+#[derive(Clone, Debug)]
+pub(crate) struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            cooldown,
+            consecutive_failures: 0,
+            open_until: None,
+        }
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        self.open_until
+            .is_some_and(|open_until| Instant::now() < open_until)
+    }
+
+    pub(crate) fn record(&mut self, success: bool) {
+        if success {
+            self.consecutive_failures = 0;
+            self.open_until = None;
+        } else {
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+            if self.consecutive_failures >= self.threshold {
+                self.open_until = Some(Instant::now() + self.cooldown);
+            }
+        }
+    }
+}
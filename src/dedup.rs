@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+#[derive(Deserialize)]
+struct Line<'a> {
+    #[serde(borrow)]
+    metric: &'a RawValue,
+    values: Vec<serde_json::Value>,
+    timestamps: Vec<i64>,
+}
+
+#[derive(Serialize)]
+struct MergedLine<'a> {
+    metric: &'a RawValue,
+    values: Vec<&'a serde_json::Value>,
+    timestamps: Vec<i64>,
+}
+
+enum Entry<'a> {
+    Series {
+        metric: &'a RawValue,
+        samples: BTreeMap<i64, serde_json::Value>,
+    },
+    Unparsed(&'a [u8]),
+}
+
+/// Merges all lines of `body` belonging to the same series into one,
+/// keeping only the last value buffered for each timestamp. Series keep the
+/// position and label order of their first line, and their samples are
+/// written in timestamp order. Lines that cannot be parsed are kept as-is.
+pub(crate) fn last_write_wins(body: &[u8]) -> Vec<u8> {
+    let mut entries = Vec::new();
+    let mut index: BTreeMap<BTreeMap<String, String>, usize> = BTreeMap::new();
+    for line in body.split(|b| *b == b'\n') {
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        let parsed = serde_json::from_slice::<Line>(line)
+            .ok()
+            .and_then(|parsed| {
+                let key = serde_json::from_str(parsed.metric.get()).ok()?;
+                Some((key, parsed))
+            });
+        let (key, parsed) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                entries.push(Entry::Unparsed(line));
+                continue;
+            }
+        };
+        let position = *index.entry(key).or_insert_with(|| {
+            entries.push(Entry::Series {
+                metric: parsed.metric,
+                samples: BTreeMap::new(),
+            });
+            entries.len() - 1
+        });
+        if let Entry::Series { samples, .. } = &mut entries[position] {
+            samples.extend(parsed.timestamps.into_iter().zip(parsed.values));
+        }
+    }
+
+    let mut merged = Vec::with_capacity(body.len());
+    for entry in entries {
+        match entry {
+            Entry::Series { metric, samples } => {
+                let line = MergedLine {
+                    metric,
+                    values: samples.values().collect(),
+                    timestamps: samples.keys().copied().collect(),
+                };
+                serde_json::to_writer(&mut merged, &line).unwrap();
+                merged.extend_from_slice(b"\r\n");
+            }
+            Entry::Unparsed(line) => {
+                merged.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+                merged.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unparsed_lines_are_kept() {
+        let body =
+            b"not json\r\n{\"metric\":{\"__name__\":\"up\"},\"values\":[1],\"timestamps\":[1]}\r\n";
+        assert_eq!(last_write_wins(body), body);
+    }
+}
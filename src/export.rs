@@ -0,0 +1,79 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+
+use crate::{ErrorParser, MetricData, SendError};
+
+#[derive(Deserialize)]
+struct ExportedMetric {
+    metric: BTreeMap<String, String>,
+    values: Vec<serde_json::Value>,
+    timestamps: Vec<i64>,
+}
+
+/// Sends `request` and streams the JSON lines of the response as metrics.
+pub(crate) fn export_stream(
+    request: RequestBuilder,
+    error_parser: Arc<ErrorParser>,
+) -> impl Stream<Item = Result<MetricData, SendError>> {
+    stream::once(async move {
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            // As when importing, a body that fails to read must not hide
+            // the status.
+            let message = match response.bytes().await {
+                Ok(body) => error_parser(status, &body),
+                Err(err) => format!("<unreadable response body: {}>", err),
+            };
+            return Err(SendError::InvalidResponseStatusCode(status, message));
+        }
+        Ok(parse_lines(response.bytes_stream()))
+    })
+    .try_flatten()
+}
+
+/// Yields one metric per line of `body`, whose chunks may split lines
+/// anywhere.
+fn parse_lines(
+    body: impl Stream<Item = reqwest::Result<bytes::Bytes>> + Send + 'static,
+) -> impl Stream<Item = Result<MetricData, SendError>> {
+    stream::try_unfold(
+        (body.boxed(), Vec::new()),
+        |(mut body, mut buffer)| async move {
+            loop {
+                if let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=end).collect();
+                    if line.trim_ascii().is_empty() {
+                        continue;
+                    }
+                    return Ok(Some((parse_line(&line)?, (body, buffer))));
+                }
+                match body.next().await {
+                    Some(chunk) => buffer.extend_from_slice(&chunk?),
+                    None if buffer.trim_ascii().is_empty() => return Ok(None),
+                    None => {
+                        let line = std::mem::take(&mut buffer);
+                        return Ok(Some((parse_line(&line)?, (body, buffer))));
+                    }
+                }
+            }
+        },
+    )
+}
+
+fn parse_line(line: &[u8]) -> Result<MetricData, SendError> {
+    let ExportedMetric {
+        mut metric,
+        values,
+        timestamps,
+    } = serde_json::from_slice(line)?;
+    Ok(MetricData {
+        name: metric.remove("__name__").unwrap_or_default(),
+        labels: metric,
+        values,
+        timestamps,
+    })
+}
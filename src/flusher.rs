@@ -0,0 +1,112 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::Mutex,
+    task::JoinHandle,
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{FlushReason, MetricsWriter};
+
+/// When a background flusher started with [`spawn_flusher`] sends.
+#[derive(Clone, Copy, Debug)]
+pub struct FlushSchedule {
+    period: Duration,
+    missed_tick_behavior: MissedTickBehavior,
+}
+
+impl FlushSchedule {
+    /// Flushes every `period`, starting right away.
+    pub fn new(period: Duration) -> Self {
+        FlushSchedule {
+            period,
+            missed_tick_behavior: MissedTickBehavior::Delay,
+        }
+    }
+
+    /// Sets what happens when a flush takes longer than the period. Defaults
+    /// to [`MissedTickBehavior::Delay`]: after a slow flush the next one starts
+    /// right away and later ones follow a full period apart, rather than
+    /// catching up on every missed tick in a burst.
+    pub fn with_missed_tick_behavior(mut self, behavior: MissedTickBehavior) -> Self {
+        self.missed_tick_behavior = behavior;
+        self
+    }
+
+    pub(crate) async fn run<F, Fut>(self, mut flush: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut ticks = interval(self.period);
+        ticks.set_missed_tick_behavior(self.missed_tick_behavior);
+        loop {
+            ticks.tick().await;
+            flush().await;
+        }
+    }
+}
+
+/// Spawns a task sending `writer`'s buffer on `schedule` until the returned
+/// handle is aborted. Send errors are not reported by the task; check
+/// [`MetricsWriter::last_send_ok`] or [`MetricsWriter::stats`] instead.
+pub fn spawn_flusher(writer: Arc<Mutex<MetricsWriter>>, schedule: FlushSchedule) -> JoinHandle<()> {
+    tokio::spawn(schedule.run(move || {
+        let writer = writer.clone();
+        async move {
+            let _ = writer
+                .lock()
+                .await
+                .send_with_reason(FlushReason::Timer)
+                .await;
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+
+    /// Runs `schedule` for a second with the second flush taking 450ms and
+    /// returns when each flush started, in milliseconds.
+    async fn flush_times(schedule: FlushSchedule) -> Vec<u64> {
+        let start = Instant::now();
+        let times = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let task = {
+            let times = times.clone();
+            tokio::spawn(schedule.run(move || {
+                let times = times.clone();
+                async move {
+                    let count = {
+                        let mut times = times.lock().unwrap();
+                        times.push(start.elapsed().as_millis() as u64);
+                        times.len()
+                    };
+                    if count == 2 {
+                        tokio::time::sleep(Duration::from_millis(450)).await;
+                    }
+                }
+            }))
+        };
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        task.abort();
+        let times = times.lock().unwrap().clone();
+        times
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_missed_tick_behavior() {
+        let schedule = FlushSchedule::new(Duration::from_millis(200));
+        assert_eq!(flush_times(schedule).await, [0, 200, 650, 850]);
+        assert_eq!(
+            flush_times(schedule.with_missed_tick_behavior(MissedTickBehavior::Skip)).await,
+            [0, 200, 650, 800]
+        );
+        assert_eq!(
+            flush_times(schedule.with_missed_tick_behavior(MissedTickBehavior::Burst)).await,
+            [0, 200, 650, 650, 800]
+        );
+    }
+}
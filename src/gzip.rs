@@ -0,0 +1,75 @@
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+
+/// Gzip compression level for request bodies, see
+/// [`MetricsWriter::with_gzip`](crate::MetricsWriter::with_gzip).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GzipLevel {
+    Fast,
+    #[default]
+    Default,
+    Best,
+    /// An explicit level from 0 (no compression) to 9 (best); higher values
+    /// are treated as 9.
+    Level(u32),
+}
+
+impl GzipLevel {
+    fn compression(self) -> Compression {
+        match self {
+            GzipLevel::Fast => Compression::fast(),
+            GzipLevel::Default => Compression::default(),
+            GzipLevel::Best => Compression::best(),
+            GzipLevel::Level(level) => Compression::new(level.min(9)),
+        }
+    }
+}
+
+pub(crate) fn compress(body: &[u8], level: GzipLevel) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), level.compression());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn decompress(body: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        GzDecoder::new(body).read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn test_levels() {
+        let body: Vec<u8> = (0..200)
+            .map(|i| {
+                format!(
+                    r#"{{"metric":{{"__name__":"up","instance":"host-{}"}},"values":[{}],"timestamps":[{}]}}"#,
+                    i % 7,
+                    i,
+                    1549891472010i64 + i * 15000
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\r\n")
+            .into_bytes();
+
+        let stored = compress(&body, GzipLevel::Level(0));
+        let fast = compress(&body, GzipLevel::Fast);
+        let best = compress(&body, GzipLevel::Best);
+        for compressed in [&stored, &fast, &best] {
+            assert_eq!(decompress(compressed), body);
+        }
+        assert!(stored.len() > body.len());
+        assert!(fast.len() < stored.len());
+        assert!(best.len() <= fast.len());
+        assert_eq!(compress(&body, GzipLevel::Level(42)), best);
+    }
+}
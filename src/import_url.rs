@@ -0,0 +1,243 @@
+use url::{form_urlencoded, Url};
+
+/// Describes where import requests are sent, see [`build_import_url`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportUrlConfig {
+    /// `http` or `https`.
+    pub scheme: String,
+    /// Host and optional port, e.g. `localhost:8428`.
+    pub host: String,
+    /// Path prepended to the import path, for instances behind a reverse
+    /// proxy, e.g. `/victoria-metrics`.
+    pub path_prefix: Option<String>,
+    /// Cluster tenant as `accountID` or `accountID:projectID`. When set, the
+    /// URL points at `vminsert`'s tenant-specific import path.
+    pub tenant: Option<String>,
+    /// Labels Victoria Metrics adds to every imported sample, sent as
+    /// `extra_label` query parameters.
+    pub extra_labels: Vec<(String, String)>,
+    /// Arbitrary query parameters appended after the extra labels, e.g. for
+    /// debug flags of newer Victoria Metrics versions.
+    pub query_params: Vec<(String, String)>,
+}
+
+impl ImportUrlConfig {
+    pub fn new(host: &str) -> Self {
+        ImportUrlConfig {
+            scheme: "http".to_owned(),
+            host: host.to_owned(),
+            path_prefix: None,
+            tenant: None,
+            extra_labels: Vec::new(),
+            query_params: Vec::new(),
+        }
+    }
+}
+
+/// Builds the JSON import URL for `config`, e.g.
+/// `http://localhost:8428/api/v1/import` for a single node or
+/// `http://vminsert:8480/insert/1:2/prometheus/api/v1/import` for a cluster
+/// tenant.
+pub fn build_import_url(config: &ImportUrlConfig) -> String {
+    let mut url = base_url(config, "insert");
+    url.push_str("/api/v1/import");
+
+    if !config.extra_labels.is_empty() || !config.query_params.is_empty() {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for (key, value) in &config.extra_labels {
+            query.append_pair("extra_label", &format!("{}={}", key, value));
+        }
+        for (key, value) in &config.query_params {
+            query.append_pair(key, value);
+        }
+        url.push('?');
+        url.push_str(&query.finish());
+    }
+    url
+}
+
+/// Like [`build_import_url`], but first checks that the tenant has the
+/// `accountID[:projectID]` format, that the path prefix is a plain path and
+/// that the result parses as an HTTP(S) URL, describing the problem
+/// otherwise.
+pub(crate) fn checked_import_url(config: &ImportUrlConfig) -> Result<String, String> {
+    if let Some(tenant) = &config.tenant {
+        if !is_valid_tenant(tenant) {
+            return Err(format!(
+                "tenant {:?} is not of the form accountID or accountID:projectID",
+                tenant
+            ));
+        }
+    }
+    if config.scheme != "http" && config.scheme != "https" {
+        return Err(format!("unsupported scheme {:?}", config.scheme));
+    }
+
+    if config.host.is_empty() || config.host.contains(['/', '?', '#', '@']) {
+        return Err(format!("invalid host {:?}", config.host));
+    }
+    if let Some(prefix) = &config.path_prefix {
+        if !is_valid_path_prefix(prefix) {
+            return Err(format!(
+                "path prefix {:?} does not start with / or contains ? or #",
+                prefix
+            ));
+        }
+    }
+
+    let url = build_import_url(config);
+    match Url::parse(&url) {
+        Ok(_) => Ok(url),
+        Err(err) => Err(format!("{}: {}", url, err)),
+    }
+}
+
+/// Returns whether `tenant` has the `accountID[:projectID]` format.
+pub(crate) fn is_valid_tenant(tenant: &str) -> bool {
+    let is_id = |id: &str| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit());
+    match tenant.split_once(':') {
+        Some((account, project)) => is_id(account) && is_id(project),
+        None => is_id(tenant),
+    }
+}
+
+/// Returns whether `prefix` is a plain path, starting with `/` and
+/// without a query or fragment.
+pub(crate) fn is_valid_path_prefix(prefix: &str) -> bool {
+    prefix.starts_with('/') && !prefix.contains(['?', '#'])
+}
+
+/// Builds the JSON export URL matching `config`, selecting series matching
+/// `selector` between the optional RFC 3339 `start` and `end`. Cluster
+/// tenants are read from `vmselect`'s tenant-specific path.
+pub(crate) fn build_export_url(
+    config: &ImportUrlConfig,
+    selector: &str,
+    start: Option<&str>,
+    end: Option<&str>,
+) -> String {
+    let mut url = base_url(config, "select");
+    url.push_str("/api/v1/export?");
+
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    query.append_pair("match[]", selector);
+    if let Some(start) = start {
+        query.append_pair("start", start);
+    }
+    if let Some(end) = end {
+        query.append_pair("end", end);
+    }
+    url.push_str(&query.finish());
+    url
+}
+
+/// The scheme, host, path prefix and, for tenants, the cluster component's
+/// `/<component>/<tenant>/prometheus` path.
+fn base_url(config: &ImportUrlConfig, component: &str) -> String {
+    let mut url = format!("{}://{}", config.scheme, config.host);
+    if let Some(prefix) = &config.path_prefix {
+        url.push_str(prefix.trim_end_matches('/'));
+    }
+    if let Some(tenant) = &config.tenant {
+        url.push_str(&format!("/{}/{}/prometheus", component, tenant));
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_node() {
+        assert_eq!(
+            build_import_url(&ImportUrlConfig::new("localhost:8428")),
+            "http://localhost:8428/api/v1/import"
+        );
+    }
+
+    #[test]
+    fn test_cluster_tenant_over_https() {
+        let config = ImportUrlConfig {
+            scheme: "https".to_owned(),
+            tenant: Some("1:2".to_owned()),
+            ..ImportUrlConfig::new("vminsert:8480")
+        };
+        assert_eq!(
+            build_import_url(&config),
+            "https://vminsert:8480/insert/1:2/prometheus/api/v1/import"
+        );
+    }
+
+    #[test]
+    fn test_path_prefix_and_extra_labels() {
+        let config = ImportUrlConfig {
+            path_prefix: Some("/vm/".to_owned()),
+            tenant: Some("7".to_owned()),
+            extra_labels: vec![
+                ("env".to_owned(), "prod".to_owned()),
+                ("region".to_owned(), "eu west".to_owned()),
+            ],
+            ..ImportUrlConfig::new("proxy")
+        };
+        assert_eq!(
+            build_import_url(&config),
+            "http://proxy/vm/insert/7/prometheus/api/v1/import?extra_label=env%3Dprod&extra_label=region%3Deu+west"
+        );
+    }
+
+    #[test]
+    fn test_query_params() {
+        let config = ImportUrlConfig {
+            extra_labels: vec![("env".to_owned(), "prod".to_owned())],
+            query_params: vec![
+                ("debug".to_owned(), "1".to_owned()),
+                ("debug".to_owned(), "verbose".to_owned()),
+            ],
+            ..ImportUrlConfig::new("localhost:8428")
+        };
+        assert_eq!(
+            build_import_url(&config),
+            "http://localhost:8428/api/v1/import?extra_label=env%3Dprod&debug=1&debug=verbose"
+        );
+    }
+
+    #[test]
+    fn test_checked_import_url() {
+        let config = |host: &str, tenant: Option<&str>| ImportUrlConfig {
+            tenant: tenant.map(str::to_owned),
+            ..ImportUrlConfig::new(host)
+        };
+        assert_eq!(
+            checked_import_url(&config("vminsert:8480", Some("1:2"))).unwrap(),
+            "http://vminsert:8480/insert/1:2/prometheus/api/v1/import"
+        );
+        for tenant in ["", "a", "1:", ":2", "1/2", "1:2:3"] {
+            let err = checked_import_url(&config("vminsert:8480", Some(tenant))).unwrap_err();
+            assert!(err.starts_with("tenant"), "{}", err);
+        }
+        assert!(checked_import_url(&config("local host", None)).is_err());
+        assert!(checked_import_url(&config("", None)).is_err());
+
+        for prefix in ["vm", "/vm?debug=1", "/vm#top", ""] {
+            let config = ImportUrlConfig {
+                path_prefix: Some(prefix.to_owned()),
+                ..ImportUrlConfig::new("proxy")
+            };
+            let err = checked_import_url(&config).unwrap_err();
+            assert!(err.starts_with("path prefix"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_export_url() {
+        let config = ImportUrlConfig {
+            tenant: Some("1".to_owned()),
+            ..ImportUrlConfig::new("vmselect:8481")
+        };
+        assert_eq!(
+            build_export_url(&config, r#"{job="a"}"#, Some("2019-02-11T13:24:32Z"), None),
+            "http://vmselect:8481/select/1/prometheus/api/v1/export?match%5B%5D=%7Bjob%3D%22a%22%7D&start=2019-02-11T13%3A24%3A32Z"
+        );
+    }
+}
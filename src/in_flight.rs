@@ -0,0 +1,27 @@
+use std::sync::Arc;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Caps the number of concurrent import requests. Clones share the same
+/// limit, so one `InFlightLimit` handed to every writer bounds the whole
+/// application.
+#[derive(Clone, Debug)]
+pub struct InFlightLimit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl InFlightLimit {
+    /// Allows at most `max` requests in flight at once; `0` is treated as `1`.
+    pub fn new(max: usize) -> Self {
+        InFlightLimit {
+            semaphore: Arc::new(Semaphore::new(max.max(1))),
+        }
+    }
+
+    pub(crate) async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.semaphore
+            .acquire()
+            .await
+            .expect("in-flight semaphore is never closed")
+    }
+}
@@ -2,7 +2,12 @@
 A Rust library for writing data to Victoria Metrics' JSON import endpoint. More information [here](https://docs.victoriametrics.com/#how-to-import-data-in-json-line-format).
 
 # Usage
-```rust
+```rust,no_run
+# use std::collections::BTreeMap;
+# use chrono::{TimeZone, Utc};
+# use victoria_metrics_writer::MetricsWriter;
+# #[tokio::main(flavor = "current_thread")]
+# async fn main() -> Result<(), Box<dyn std::error::Error>> {
 let mut writer = MetricsWriter::new("localhost:8428");
 
 writer.add(
@@ -14,146 +19,4844 @@ writer.add(
         Utc.timestamp_millis_opt(1549891487724).unwrap(),
         Utc.timestamp_millis_opt(1549891503438).unwrap(),
     ],
-);
+)?;
 
 writer.send().await?;
+# Ok(())
+# }
 ```
 */
 
-use std::{collections::BTreeMap, io::Write};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::{self, Display},
+    future::Future,
+    path::Path,
+    str::Utf8Error,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use bytes::{buf::Writer, BufMut};
-use chrono::{DateTime, Utc};
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::Stream;
 use reqwest::StatusCode;
-use serde::Serialize;
+use serde::{de::IgnoredAny, ser::SerializeMap, Deserialize, Serialize, Serializer};
 
 use thiserror::Error;
 
+use buffer::Buffer;
+use circuit_breaker::CircuitBreaker;
+use rate_limit::RateLimiter;
+use redact::RedactedValues;
+use retry::RetryPolicy;
+use value_kind::{value_kind, KindError};
+
+mod buffer;
+mod buffer_writer;
+mod circuit_breaker;
+mod dedup;
+mod export;
+mod flusher;
+mod gzip;
+mod import_url;
+mod in_flight;
+mod noop;
+mod rate_limit;
+#[cfg(feature = "metrics")]
+mod recorder;
+mod redact;
+mod retry;
+mod sink;
+mod value_kind;
+
+pub use buffer_writer::BufferWriter;
+pub use flusher::{spawn_flusher, FlushSchedule};
+pub use gzip::GzipLevel;
+pub use import_url::{build_import_url, ImportUrlConfig};
+pub use in_flight::InFlightLimit;
+pub use noop::NoopMetricsWriter;
+#[cfg(feature = "metrics")]
+pub use recorder::{VictoriaMetricsRecorder, DEFAULT_BUCKETS};
+pub use sink::{MetricValue, MetricsSink, SendFuture};
+
 pub struct MetricsWriter {
-    url: String,
+    url_config: ImportUrlConfig,
+    /// Validated import URLs by tenant, built on first use and dropped
+    /// whenever `url_config` changes.
+    import_urls: BTreeMap<Option<String>, String>,
     client: reqwest::Client,
-    writer: Option<Writer<Vec<u8>>>,
+    #[cfg(feature = "middleware")]
+    middleware_client: Option<reqwest_middleware::ClientWithMiddleware>,
+    client_options: ClientOptions,
+    buffer: Buffer,
+    tenants: BTreeMap<String, Buffer>,
+    precision: TimestampPrecision,
+    stats: WriterStats,
+    latencies: VecDeque<Duration>,
+    self_metrics: Option<String>,
+    required_labels: Vec<String>,
+    clamp_pre_epoch: bool,
+    transform: Option<Box<Transform>>,
+    redacted_labels: BTreeSet<String>,
+    redacted_values: RedactedValues,
+    strict_value_types: bool,
+    value_types: BTreeMap<String, ValueKind>,
+    gzip: Option<GzipLevel>,
+    gzip_min_size: usize,
+    gzip_detection: bool,
+    gzip_supported: Option<bool>,
+    circuit_breaker: Option<CircuitBreaker>,
+    accepted_statuses: Vec<StatusCode>,
+    max_series: Option<usize>,
+    series: BTreeSet<SeriesKey>,
+    monotonic_timestamps: bool,
+    duplicate_labels: DuplicateLabelPolicy,
+    last_write_wins: bool,
+    last_send_ok: Option<bool>,
+    on_flush: Option<Box<FlushCallback>>,
+    snapshots: Vec<MetricData>,
+    clock: Box<Clock>,
+    normalize_names: bool,
+    max_series_per_flush: Option<usize>,
+    batch_series: BTreeSet<SeriesKey>,
+    rate_limiter: Option<RateLimiter>,
+    retry: Option<RetryPolicy>,
+    error_parser: Arc<ErrorParser>,
+    bearer_token: Option<String>,
+    in_flight: Option<InFlightLimit>,
+    /// While adding several series as one, the changes to undo if a later
+    /// one fails, see [`MetricsWriter::add_all_or_nothing`].
+    undo: Option<Vec<Undo>>,
+}
+
+/// A change to the series bookkeeping made while adding a line.
+enum Undo {
+    Series(SeriesKey),
+    BatchSeries(SeriesKey),
+    /// A new batch was started, clearing these series of the old one.
+    Sealed(BTreeSet<SeriesKey>),
+    ValueType(String, Option<ValueKind>),
+}
+
+/// The `add`/`send` surface shared by [`MetricsWriter`] and
+/// [`NoopMetricsWriter`], so code can be generic over whether metrics are
+/// enabled.
+pub trait WriteMetrics {
+    fn add<T, L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized;
+
+    fn send(&mut self) -> impl Future<Output = Result<(), SendError>> + Send;
+}
+
+impl WriteMetrics for MetricsWriter {
+    fn add<T, L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        MetricsWriter::add(self, name, labels, values, timestamps)
+    }
+
+    fn send(&mut self) -> impl Future<Output = Result<(), SendError>> + Send {
+        MetricsWriter::send(self)
+    }
+}
+
+impl Default for MetricsWriter {
+    fn default() -> Self {
+        MetricsWriter::localhost()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ClientOptions {
+    gzip: bool,
+    tcp_keepalive: Option<Duration>,
+    tcp_nodelay: bool,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            gzip: true,
+            tcp_keepalive: None,
+            tcp_nodelay: true,
+        }
+    }
+}
+
+impl ClientOptions {
+    fn build(&self) -> reqwest::Client {
+        let builder = reqwest::Client::builder()
+            .gzip(self.gzip)
+            .tcp_keepalive(self.tcp_keepalive)
+            .tcp_nodelay(self.tcp_nodelay);
+        #[cfg(feature = "rustls-tls")]
+        let builder = builder.use_rustls_tls();
+        builder.build().expect("failed to build HTTP client")
+    }
+}
+
+type Transform = dyn Fn(&mut MetricData) -> bool + Send + Sync;
+
+type Clock = dyn Fn() -> DateTime<Utc> + Send + Sync;
+
+type FlushCallback = dyn Fn(&SendOutcome) + Send + Sync;
+
+type ErrorParser = dyn Fn(StatusCode, &[u8]) -> String + Send + Sync;
+
+/// The default error body parser: the body as text, with surrounding
+/// whitespace trimmed.
+pub fn text_error_parser(_status: StatusCode, body: &[u8]) -> String {
+    String::from_utf8_lossy(body).trim().to_owned()
+}
+
+/// Counters describing what a [`MetricsWriter`] has sent so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriterStats {
+    pub requests: u64,
+    pub bytes: u64,
+    pub failures: u64,
+    /// Shortest, longest and average latency of the last
+    /// [`RECENT_LATENCIES`] requests, or `None` before the first request.
+    pub latency_min: Option<Duration>,
+    pub latency_max: Option<Duration>,
+    pub latency_avg: Option<Duration>,
+}
+
+/// How many of the most recent request latencies [`WriterStats`] covers.
+pub const RECENT_LATENCIES: usize = 100;
+
+/// Why a [`MetricsWriter`] sent its buffer, reported in [`SendOutcome`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushReason {
+    /// [`MetricsWriter::send`] or [`MetricsWriter::send_with_deadline`].
+    Manual,
+    /// The predicate passed to [`MetricsWriter::add_with_flush`], e.g. a
+    /// size or count threshold.
+    Threshold,
+    /// A background flusher started with [`spawn_flusher`].
+    Timer,
+    /// A final send before shutting down, see
+    /// [`MetricsWriter::send_with_reason`].
+    Shutdown,
+}
+
+/// Passed to the callback registered with [`MetricsWriter::with_on_flush`]
+/// after every send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendOutcome {
+    pub reason: FlushReason,
+    /// Lines that were buffered when the send started.
+    pub lines: usize,
+    pub success: bool,
+}
+
+/// The size of what a [`MetricsWriter`] currently has buffered, across all
+/// tenants.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferStats {
+    pub bytes: usize,
+    pub lines: usize,
+}
+
+/// Precision that timestamps are truncated to before being buffered.
+///
+/// Victoria Metrics' JSON import always expects milliseconds, so coarser
+/// precisions are still written in milliseconds, rounded down to a whole unit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampPrecision {
+    Seconds,
+    #[default]
+    Milliseconds,
+}
+
+impl TimestampPrecision {
+    fn millis(self, ts: &DateTime<Utc>) -> i64 {
+        match self {
+            TimestampPrecision::Seconds => ts.timestamp() * 1000,
+            TimestampPrecision::Milliseconds => ts.timestamp_millis(),
+        }
+    }
+}
+
+/// What `add` does when the labels passed to it contain the same key more
+/// than once, which slices and other non-map [`Labels`] allow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateLabelPolicy {
+    /// Fail with [`AddError::DuplicateLabel`].
+    #[default]
+    Error,
+    /// Keep the first value visited for the key.
+    KeepFirst,
+    /// Keep the last value visited for the key, like collecting the labels
+    /// into a `BTreeMap` would.
+    KeepLast,
+}
+
+/// Epoch-millisecond timestamps, either given as such or converted from
+/// `DateTime`s while iterating, so that `add` needs no temporary `Vec`.
+#[derive(Clone, Copy)]
+enum Timestamps<'a> {
+    Millis(&'a [i64]),
+    DateTimes {
+        timestamps: &'a [DateTime<Utc>],
+        precision: TimestampPrecision,
+        clamp_pre_epoch: bool,
+    },
+}
+
+impl<'a> Timestamps<'a> {
+    fn iter(self) -> impl Iterator<Item = i64> + 'a {
+        let (millis, date_times, precision, clamp_pre_epoch) = match self {
+            Timestamps::Millis(millis) => (millis, &[][..], TimestampPrecision::default(), false),
+            Timestamps::DateTimes {
+                timestamps,
+                precision,
+                clamp_pre_epoch,
+            } => (&[][..], timestamps, precision, clamp_pre_epoch),
+        };
+        millis
+            .iter()
+            .copied()
+            .chain(date_times.iter().map(move |ts| {
+                let ts = precision.millis(ts);
+                if clamp_pre_epoch {
+                    ts.max(0)
+                } else {
+                    ts
+                }
+            }))
+    }
+}
+
+impl Serialize for Timestamps<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum SendError {
     #[error("error sending request")]
     RequestError(#[from] reqwest::Error),
-    #[error("invalid response status code {0}")]
-    InvalidResponseStatusCode(StatusCode),
+    #[error("invalid response status code {0}: {1}")]
+    InvalidResponseStatusCode(StatusCode, String),
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+    #[error("error reading file")]
+    IoError(#[from] std::io::Error),
+    #[error("circuit breaker is open")]
+    CircuitOpen,
+    #[error("invalid import URL: {0}")]
+    InvalidUrl(String),
+    #[error("invalid response body")]
+    InvalidResponseBody(#[from] serde_json::Error),
+    #[cfg(feature = "middleware")]
+    #[error("middleware error")]
+    MiddlewareError(#[from] reqwest_middleware::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum AddError {
+    #[error("invalid metric shape: {0}")]
+    InvalidShape(&'static str),
+    #[error("missing required label {0:?}")]
+    MissingRequiredLabel(String),
+    #[error("series {0:?} mixes integer and float values")]
+    ValueTypeConflict(String),
+    #[error("timestamps are not strictly increasing")]
+    NonMonotonicTimestamps,
+    #[error("buffer already holds the maximum of {0} distinct series")]
+    CardinalityLimitExceeded(usize),
+    #[error("duplicate label {0:?}")]
+    DuplicateLabel(String),
+    #[error("tenant {0:?} is not of the form accountID or accountID:projectID")]
+    InvalidTenant(String),
+    #[error("series {0:?} has a NaN or infinite value")]
+    NonFiniteValue(String),
+}
+
+/// Returned by [`MetricsWriter::add_with_flush`], which can fail while adding
+/// or while sending.
+#[derive(Error, Debug)]
+pub enum FlushError {
+    #[error(transparent)]
+    AddError(#[from] AddError),
+    #[error(transparent)]
+    SendError(#[from] SendError),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("missing environment variable {0}")]
+    MissingVariable(&'static str),
+    #[error("invalid value {1:?} for environment variable {0}")]
+    InvalidVariable(&'static str, String),
+    #[error("invalid import URL: {0}")]
+    InvalidUrl(String),
+}
+
+/// Identifies a series by tenant and its labels, including `__name__`.
+type SeriesKey = (Option<String>, BTreeMap<String, String>);
+
+fn series_key<L: Labels + ?Sized>(tenant: Option<&str>, name: &str, labels: &L) -> SeriesKey {
+    let mut key = BTreeMap::from([("__name__".to_owned(), name.to_owned())]);
+    labels.for_each_label(&mut |k, v| {
+        key.insert(k.to_owned(), v.to_owned());
+    });
+    (tenant.map(str::to_owned), key)
+}
+
+fn json_series_key(tenant: Option<&str>, metric: &serde_json::Value) -> SeriesKey {
+    let key = metric["metric"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(k, v)| (k.clone(), v.as_str().unwrap_or_default().to_owned()))
+        .collect();
+    (tenant.map(str::to_owned), key)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ValueKind {
+    Integer,
+    Float,
+}
+
+/// A set of labels that can be written to a metric without first being
+/// collected into a `BTreeMap`.
+///
+/// Labels are written in the order they are visited. Slices and arrays of
+/// pairs are written as given, so pairs sorted by key produce the same output
+/// as the equivalent `BTreeMap`.
+pub trait Labels {
+    fn for_each_label(&self, f: &mut dyn FnMut(&str, &str));
+
+    /// Whether every key is known to be visited only once, as for maps.
+    /// `add` then skips its check for duplicate keys. Defaults to `false`.
+    fn is_unique(&self) -> bool {
+        false
+    }
+}
+
+impl<K: AsRef<str>, V: AsRef<str>> Labels for BTreeMap<K, V> {
+    fn for_each_label(&self, f: &mut dyn FnMut(&str, &str)) {
+        for (key, value) in self {
+            f(key.as_ref(), value.as_ref());
+        }
+    }
+
+    fn is_unique(&self) -> bool {
+        true
+    }
+}
+
+impl<K: AsRef<str>, V: AsRef<str>> Labels for [(K, V)] {
+    fn for_each_label(&self, f: &mut dyn FnMut(&str, &str)) {
+        for (key, value) in self {
+            f(key.as_ref(), value.as_ref());
+        }
+    }
+}
+
+impl<K: AsRef<str>, V: AsRef<str>, const N: usize> Labels for [(K, V); N] {
+    fn for_each_label(&self, f: &mut dyn FnMut(&str, &str)) {
+        self[..].for_each_label(f)
+    }
+}
+
+impl<K: AsRef<str>, V: AsRef<str>> Labels for Vec<(K, V)> {
+    fn for_each_label(&self, f: &mut dyn FnMut(&str, &str)) {
+        self[..].for_each_label(f)
+    }
+}
+
+/// Label pairs whose values only need to implement `Display`, such as shard
+/// numbers or enums, so callers don't have to format them up front:
+///
+/// ```
+/// # use victoria_metrics_writer::DisplayLabels;
+/// let labels = DisplayLabels(&[("job", &"api"), ("shard", &3)]);
+/// ```
+pub struct DisplayLabels<'a>(pub &'a [(&'a str, &'a dyn Display)]);
+
+impl Labels for DisplayLabels<'_> {
+    fn for_each_label(&self, f: &mut dyn FnMut(&str, &str)) {
+        let mut value = String::new();
+        for (key, display) in self.0 {
+            value.clear();
+            fmt::write(&mut value, format_args!("{}", display)).unwrap();
+            f(key, &value);
+        }
+    }
+}
+
+/// A series currently held in a writer's buffer, see
+/// [`MetricsWriter::buffered_series`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeriesInfo {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub samples: usize,
+}
+
+/// An owned, mutable view of a metric passed to the transform registered
+/// with [`MetricsWriter::with_transform`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricData {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub values: Vec<serde_json::Value>,
+    pub timestamps: Vec<i64>,
+}
+
+impl MetricData {
+    fn new<T, L>(name: &str, labels: &L, values: &[T], timestamps: Timestamps) -> Self
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        let mut owned_labels = BTreeMap::new();
+        labels.for_each_label(&mut |key, value| {
+            owned_labels.insert(key.to_owned(), value.to_owned());
+        });
+        MetricData {
+            name: name.to_owned(),
+            labels: owned_labels,
+            values: values
+                .iter()
+                .map(|value| serde_json::to_value(value).unwrap())
+                .collect(),
+            timestamps: timestamps.iter().collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BufferedMetric {
+    metric: BTreeMap<String, String>,
+    values: Vec<IgnoredAny>,
+}
+
+/// Returns the first label key that `labels` visits more than once.
+///
+/// Keys are told apart by hash in a single pass without allocating for up
+/// to 16 labels; only a repeated hash costs another pass to compare the
+/// keys themselves.
+fn first_duplicate_label<L: Labels + ?Sized>(labels: &L) -> Option<String> {
+    if labels.is_unique() {
+        return None;
+    }
+    let mut inline = [0u64; 16];
+    let mut spilled = Vec::new();
+    let mut duplicate = None;
+    let mut index = 0;
+    labels.for_each_label(&mut |key, _| {
+        if duplicate.is_some() {
+            return;
+        }
+        let hash = key_hash(key);
+        let repeated = inline[..index.min(inline.len())].contains(&hash) || spilled.contains(&hash);
+        if repeated && occurrences_before(labels, key, index) > 0 {
+            duplicate = Some(key.to_owned());
+        }
+        match inline.get_mut(index) {
+            Some(slot) => *slot = hash,
+            None => spilled.push(hash),
+        }
+        index += 1;
+    });
+    duplicate
+}
+
+/// FNV-1a, which is plenty for telling a handful of label keys apart.
+fn key_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Counts how often `key` is visited before position `index`.
+fn occurrences_before<L: Labels + ?Sized>(labels: &L, key: &str, index: usize) -> usize {
+    let mut count = 0;
+    let mut position = 0;
+    labels.for_each_label(&mut |other, _| {
+        if position < index && other == key {
+            count += 1;
+        }
+        position += 1;
+    });
+    count
+}
+
+/// Visits only one pair per key of `labels`: the first or the last one.
+struct UniqueLabels<'a, L: ?Sized> {
+    labels: &'a L,
+    /// Whether to visit the pair at each position, worked out once so that
+    /// visiting costs a single pass over `labels`.
+    keep: Vec<bool>,
+}
+
+impl<'a, L: Labels + ?Sized> UniqueLabels<'a, L> {
+    fn new(labels: &'a L, keep_last: bool) -> Self {
+        let mut keys = Vec::new();
+        labels.for_each_label(&mut |key, _| keys.push(key.to_owned()));
+        let keep = (0..keys.len())
+            .map(|i| {
+                let mut others = if keep_last { i + 1..keys.len() } else { 0..i };
+                !others.any(|j| keys[j] == keys[i])
+            })
+            .collect();
+        UniqueLabels { labels, keep }
+    }
+}
+
+impl<L: Labels + ?Sized> Labels for UniqueLabels<'_, L> {
+    fn for_each_label(&self, f: &mut dyn FnMut(&str, &str)) {
+        let mut index = 0;
+        self.labels.for_each_label(&mut |key, value| {
+            if self.keep.get(index).copied().unwrap_or(false) {
+                f(key, value);
+            }
+            index += 1;
+        });
+    }
+
+    fn is_unique(&self) -> bool {
+        true
+    }
+}
+
+/// Replaces characters not allowed in Prometheus metric names
+/// (`[a-zA-Z_:][a-zA-Z0-9_:]*`) or, without `metric`, label names
+/// (`[a-zA-Z_][a-zA-Z0-9_]*`) with `_`, and prefixes names starting with a
+/// digit with `_`.
+fn normalize_name(name: &str, metric: bool) -> Cow<'_, str> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || (metric && c == ':');
+    let starts_with_digit = name.starts_with(|c: char| c.is_ascii_digit());
+    if !starts_with_digit && name.chars().all(allowed) {
+        return Cow::Borrowed(name);
+    }
+    let prefix = if starts_with_digit { "_" } else { "" };
+    let normalized = name.chars().map(|c| if allowed(c) { c } else { '_' });
+    Cow::Owned(prefix.chars().chain(normalized).collect())
+}
+
+/// Visits `labels` with their keys normalized, see [`normalize_name`].
+struct NormalizedLabels<'a, L: ?Sized>(&'a L);
+
+impl<L: Labels + ?Sized> Labels for NormalizedLabels<'_, L> {
+    fn for_each_label(&self, f: &mut dyn FnMut(&str, &str)) {
+        self.0
+            .for_each_label(&mut |key, value| f(&normalize_name(key, false), value));
+    }
+}
+
+/// Visits `labels` followed by one extra pair.
+struct WithLabel<'a, L: ?Sized> {
+    labels: &'a L,
+    key: &'a str,
+    value: &'a str,
+}
+
+impl<L: Labels + ?Sized> Labels for WithLabel<'_, L> {
+    fn for_each_label(&self, f: &mut dyn FnMut(&str, &str)) {
+        self.labels.for_each_label(f);
+        f(self.key, self.value);
+    }
 }
 
 #[derive(Serialize)]
-struct Metric<'a, T> {
+#[serde(bound(serialize = "T: Serialize, L: Labels"))]
+struct Metric<'a, T, L: ?Sized> {
     #[serde(rename = "metric")]
-    meta: MetricMeta<'a>,
+    meta: MetricMeta<'a, L>,
     values: &'a [T],
-    timestamps: &'a [i64],
+    timestamps: Timestamps<'a>,
 }
 
-#[derive(Serialize)]
-struct MetricMeta<'a> {
-    #[serde(rename = "__name__")]
+struct MetricMeta<'a, L: ?Sized> {
     name: &'a str,
-    #[serde(flatten)]
-    labels: &'a BTreeMap<&'a str, &'a str>,
+    labels: &'a L,
+}
+
+impl<L: Labels + ?Sized> Serialize for MetricMeta<'_, L> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("__name__", self.name)?;
+        let mut result = Ok(());
+        self.labels.for_each_label(&mut |key, value| {
+            if result.is_ok() {
+                result = map.serialize_entry(key, value);
+            }
+        });
+        result?;
+        map.end()
+    }
 }
 
 impl MetricsWriter {
     pub fn new(host: &str) -> Self {
-        MetricsWriter {
-            url: format!("http://{}/api/v1/import", host),
-            client: reqwest::Client::new(),
-            writer: None,
+        Self::from_url_config(ImportUrlConfig::new(host))
+    }
+
+    /// Like [`MetricsWriter::new`], but with host and port given separately.
+    /// IPv6 addresses are put in brackets, e.g. `[::1]:8428`.
+    pub fn with_host_port(host: &str, port: u16) -> Self {
+        if host.contains(':') && !host.starts_with('[') {
+            Self::new(&format!("[{}]:{}", host, port))
+        } else {
+            Self::new(&format!("{}:{}", host, port))
         }
     }
 
-    pub fn add<T>(
-        &mut self,
-        name: &str,
-        labels: &BTreeMap<&str, &str>,
-        values: &[T],
-        timestamps: &[DateTime<Utc>],
-    ) where
-        T: serde::Serialize,
-    {
-        let writer = self.writer.get_or_insert_with(|| vec![].writer());
+    /// Creates a writer for a single-node instance on `localhost:8428`, the
+    /// Victoria Metrics default.
+    pub fn localhost() -> Self {
+        Self::new("localhost:8428")
+    }
 
-        let ts: Vec<i64> = timestamps.iter().map(|ts| ts.timestamp_millis()).collect();
-        let metric = Metric {
-            meta: MetricMeta { name, labels },
-            timestamps: &ts,
-            values,
-        };
-        serde_json::to_writer(writer, &metric).unwrap();
-        self.writer.as_mut().unwrap().write_all(b"\r\n").unwrap();
+    /// Creates a writer that sends to the URL described by `config`, see
+    /// [`build_import_url`]. Metrics added with
+    /// [`MetricsWriter::add_for_tenant`] use the same config with the tenant
+    /// replaced.
+    pub fn from_url_config(config: ImportUrlConfig) -> Self {
+        MetricsWriter {
+            url_config: config,
+            import_urls: BTreeMap::new(),
+            client: ClientOptions::default().build(),
+            #[cfg(feature = "middleware")]
+            middleware_client: None,
+            client_options: ClientOptions::default(),
+            buffer: Buffer::default(),
+            tenants: BTreeMap::new(),
+            precision: TimestampPrecision::default(),
+            stats: WriterStats::default(),
+            latencies: VecDeque::with_capacity(RECENT_LATENCIES),
+            self_metrics: None,
+            required_labels: Vec::new(),
+            clamp_pre_epoch: false,
+            transform: None,
+            redacted_labels: BTreeSet::new(),
+            redacted_values: RedactedValues::default(),
+            strict_value_types: false,
+            value_types: BTreeMap::new(),
+            gzip: None,
+            gzip_min_size: 0,
+            gzip_detection: false,
+            gzip_supported: None,
+            circuit_breaker: None,
+            accepted_statuses: Vec::new(),
+            max_series: None,
+            series: BTreeSet::new(),
+            monotonic_timestamps: false,
+            duplicate_labels: DuplicateLabelPolicy::default(),
+            last_write_wins: false,
+            last_send_ok: None,
+            on_flush: None,
+            snapshots: Vec::new(),
+            clock: Box::new(Utc::now),
+            normalize_names: false,
+            max_series_per_flush: None,
+            batch_series: BTreeSet::new(),
+            rate_limiter: None,
+            retry: None,
+            error_parser: Arc::new(text_error_parser),
+            bearer_token: None,
+            in_flight: None,
+            undo: None,
+        }
     }
 
-    pub async fn send(&mut self) -> Result<(), SendError> {
-        if let Some(writer) = self.writer.take() {
-            let response = self
-                .client
-                .post(&self.url)
-                .body(writer.into_inner())
-                .send()
-                .await?;
+    /// Creates a writer configured from environment variables:
+    ///
+    /// - `VM_HOST` (required): host and optional port, e.g. `localhost:8428`.
+    /// - `VM_SCHEME`: `http` (default) or `https`.
+    /// - `VM_PATH_PREFIX`: see [`ImportUrlConfig::path_prefix`].
+    /// - `VM_TENANT`: see [`ImportUrlConfig::tenant`].
+    /// - `VM_AUTH_TOKEN`: sent as a bearer token, see
+    ///   [`MetricsWriter::with_bearer_auth`].
+    ///
+    /// Empty variables count as unset. Options set with the `with_*` methods
+    /// afterwards take precedence over the environment.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_env_lookup(|key| std::env::var(key).ok())
+    }
 
-            if !response.status().is_success() {
-                return Err(SendError::InvalidResponseStatusCode(response.status()));
+    fn from_env_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let var = |key: &str| lookup(key).filter(|value| !value.is_empty());
+
+        let host = var("VM_HOST").ok_or(ConfigError::MissingVariable("VM_HOST"))?;
+        let mut config = ImportUrlConfig::new(&host);
+        if let Some(scheme) = var("VM_SCHEME") {
+            if scheme != "http" && scheme != "https" {
+                return Err(ConfigError::InvalidVariable("VM_SCHEME", scheme));
             }
+            config.scheme = scheme;
         }
-        Ok(())
+        config.path_prefix = var("VM_PATH_PREFIX");
+        config.tenant = var("VM_TENANT");
+        if let Some(tenant) = config
+            .tenant
+            .as_ref()
+            .filter(|t| !import_url::is_valid_tenant(t))
+        {
+            return Err(ConfigError::InvalidVariable("VM_TENANT", tenant.clone()));
+        }
+        if let Some(prefix) = config
+            .path_prefix
+            .as_ref()
+            .filter(|p| !import_url::is_valid_path_prefix(p))
+        {
+            return Err(ConfigError::InvalidVariable(
+                "VM_PATH_PREFIX",
+                prefix.clone(),
+            ));
+        }
+        import_url::checked_import_url(&config).map_err(ConfigError::InvalidUrl)?;
+
+        let mut writer = Self::from_url_config(config);
+        writer.bearer_token = var("VM_AUTH_TOKEN");
+        Ok(writer)
     }
 
-    #[cfg(test)]
-    fn payload(&mut self) -> Option<String> {
-        self.writer
-            .take()
-            .map(|writer| String::from_utf8(writer.into_inner()).unwrap())
+    /// Replaces the config describing the import URL, checking that it
+    /// produces a valid one.
+    pub fn with_url_config(mut self, config: ImportUrlConfig) -> Result<Self, ConfigError> {
+        import_url::checked_import_url(&config).map_err(ConfigError::InvalidUrl)?;
+        self.url_config = config;
+        self.import_urls.clear();
+        Ok(self)
     }
-}
 
-#[cfg(test)]
+    /// Sends metrics added with [`MetricsWriter::add`] to the cluster tenant
+    /// `tenant`, given as `accountID` or `accountID:projectID`.
+    pub fn with_tenant(self, tenant: &str) -> Result<Self, ConfigError> {
+        let config = ImportUrlConfig {
+            tenant: Some(tenant.to_owned()),
+            ..self.url_config.clone()
+        };
+        self.with_url_config(config)
+    }
 
-mod tests {
-    use chrono::TimeZone;
+    /// Sends `token` in an `Authorization: Bearer` header with every request,
+    /// e.g. for instances behind `vmauth`.
+    pub fn with_bearer_auth(mut self, token: &str) -> Self {
+        self.bearer_token = Some(token.to_owned());
+        self
+    }
 
-    use super::*;
-    #[test]
-    fn test_metric() {
-        let mut writer = MetricsWriter::new("localhost:8428");
+    /// Sets the default precision used by [`MetricsWriter::add`].
+    pub fn with_timestamp_precision(mut self, precision: TimestampPrecision) -> Self {
+        self.precision = precision;
+        self
+    }
 
-        writer.add(
-            "up",
-            &BTreeMap::from([("job", "node_exporter"), ("instance", "localhost:9100")]),
-            &[0, 0, 0],
-            &[
-                Utc.timestamp_millis_opt(1549891472010).unwrap(),
-                Utc.timestamp_millis_opt(1549891487724).unwrap(),
-                Utc.timestamp_millis_opt(1549891503438).unwrap(),
-            ],
-        );
+    /// Sends import requests through `client` and its middleware stack, e.g.
+    /// for tracing or caching. The client options of this writer, such as
+    /// [`MetricsWriter::with_tcp_nodelay`], do not apply to it; configure the
+    /// wrapped `reqwest::Client` instead.
+    #[cfg(feature = "middleware")]
+    pub fn with_middleware_client(
+        mut self,
+        client: reqwest_middleware::ClientWithMiddleware,
+    ) -> Self {
+        self.middleware_client = Some(client);
+        self
+    }
 
-        writer.add(
-            "up",
-            &BTreeMap::from([("job", "prometheus"), ("instance", "localhost:9090")]),
-            &[1, 1, 1],
-            &[
-                Utc.timestamp_millis_opt(1549891461511).unwrap(),
-                Utc.timestamp_millis_opt(1549891476511).unwrap(),
-                Utc.timestamp_millis_opt(1549891491511).unwrap(),
-            ],
-        );
+    /// Controls whether the client advertises `Accept-Encoding: gzip` and
+    /// transparently decompresses responses. Enabled by default.
+    pub fn with_response_decompression(mut self, enabled: bool) -> Self {
+        self.client_options.gzip = enabled;
+        self.client = self.client_options.build();
+        self
+    }
 
-        let payload = writer.payload().unwrap();
-        assert_eq!(
-            payload,
-            concat!(
-                r#"{"metric":{"__name__":"up","instance":"localhost:9100","job":"node_exporter"},"values":[0,0,0],"timestamps":[1549891472010,1549891487724,1549891503438]}"#,
-                "\r\n",
-                r#"{"metric":{"__name__":"up","instance":"localhost:9090","job":"prometheus"},"values":[1,1,1],"timestamps":[1549891461511,1549891476511,1549891491511]}"#,
-                "\r\n"
-            )
-        );
+    /// Gzip-compresses request bodies at the given level and sends them with
+    /// `Content-Encoding: gzip`.
+    pub fn with_gzip(mut self, level: GzipLevel) -> Self {
+        self.gzip = Some(level);
+        self
+    }
+
+    /// Makes [`MetricsWriter::with_gzip`] compress only if the server
+    /// supports it. Before the first compressed request, the import URL is
+    /// probed with an `OPTIONS` request and compression is used if the
+    /// response's `Accept-Encoding` header lists `gzip`. The answer is cached
+    /// for the life of the writer once the header lists `gzip` or the probe
+    /// succeeds without it; after a failed probe, requests are sent
+    /// uncompressed and the probe is retried on the next send.
+    ///
+    /// The probe is sent with the writer's own client, also when
+    /// `MetricsWriter::with_middleware_client` is set. Stock Victoria
+    /// Metrics does not advertise `Accept-Encoding` even though it accepts
+    /// gzip, so detection disables compression against it; this is meant for
+    /// proxies that do advertise it.
+    pub fn with_gzip_detection(mut self, enabled: bool) -> Self {
+        self.gzip_detection = enabled;
+        self
+    }
+
+    /// Appends `key=value` to the import URL's query string, e.g. for
+    /// ingestion flags without a dedicated option. Can be called repeatedly,
+    /// also with the same key.
+    pub fn with_query_param(mut self, key: &str, value: &str) -> Self {
+        self.url_config
+            .query_params
+            .push((key.to_owned(), value.to_owned()));
+        self.import_urls.clear();
+        self
+    }
+
+    /// Sends request bodies smaller than `bytes` uncompressed even with
+    /// [`MetricsWriter::with_gzip`] enabled, as compressing them costs CPU
+    /// for little or no gain.
+    pub fn with_gzip_min_size(mut self, bytes: usize) -> Self {
+        self.gzip_min_size = bytes;
+        self
+    }
+
+    /// Treats `status` as success in addition to any 2xx status, e.g. for a
+    /// gateway that answers with a redirect reqwest does not follow. Can be
+    /// called repeatedly.
+    pub fn with_accepted_status(mut self, status: StatusCode) -> Self {
+        self.accepted_statuses.push(status);
+        self
+    }
+
+    /// Replaces how the message of [`SendError::InvalidResponseStatusCode`]
+    /// is extracted from an error response body, e.g. to pick a field out of
+    /// a JSON body. Defaults to [`text_error_parser`].
+    pub fn with_error_parser(
+        mut self,
+        parser: impl Fn(StatusCode, &[u8]) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.error_parser = Arc::new(parser);
+        self
+    }
+
+    /// After `threshold` consecutive failed requests, makes
+    /// [`MetricsWriter::send`] return [`SendError::CircuitOpen`] right away for
+    /// `cooldown`, keeping the buffer for a later attempt.
+    pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.circuit_breaker = Some(CircuitBreaker::new(threshold, cooldown));
+        self
+    }
+
+    /// Limits sending to about `samples_per_second` samples per second on
+    /// average. Each request waits until the samples it carries fit the rate,
+    /// allowing bursts of up to one second's worth. A rate of 0 is taken as
+    /// 1.
+    pub fn with_rate_limit(mut self, samples_per_second: u32) -> Self {
+        self.rate_limiter = Some(RateLimiter::new(
+            samples_per_second,
+            tokio::time::Instant::now(),
+        ));
+        self
+    }
+
+    /// Retries an import request up to `max_retries` times after a connection
+    /// error, a 5xx or a 429 response, waiting `backoff` before the first
+    /// retry and doubling it after each one. A 429 response's `Retry-After`
+    /// header, in seconds or as an HTTP date, takes precedence over the
+    /// backoff. No retry waits longer than `max_delay`, however long the
+    /// server asks for.
+    pub fn with_retries(
+        mut self,
+        max_retries: u32,
+        backoff: Duration,
+        max_delay: Duration,
+    ) -> Self {
+        self.retry = Some(RetryPolicy::new(max_retries, backoff, max_delay));
+        self
+    }
+
+    /// Waits for a slot in `limit` before each import request. Share clones
+    /// of one [`InFlightLimit`] between writers to cap concurrent requests
+    /// across all of them.
+    pub fn with_in_flight_limit(mut self, limit: InFlightLimit) -> Self {
+        self.in_flight = Some(limit);
+        self
+    }
+
+    /// Enables TCP keepalive on the client's connections, probing idle
+    /// connections every `interval`.
+    pub fn with_tcp_keepalive(mut self, interval: Duration) -> Self {
+        self.client_options.tcp_keepalive = Some(interval);
+        self.client = self.client_options.build();
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on the client's connections. Enabled by default.
+    pub fn with_tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.client_options.tcp_nodelay = enabled;
+        self.client = self.client_options.build();
+        self
+    }
+
+    /// Makes every [`MetricsWriter::send`] that has something to send also
+    /// write the writer's own [`WriterStats`] as
+    /// `<namespace>_requests_total`, `<namespace>_bytes_total` and
+    /// `<namespace>_failures_total`. To export them periodically, send on a
+    /// schedule, e.g. with [`spawn_flusher`]; a send with an empty buffer
+    /// makes no request, so an idle writer does not keep the server busy.
+    pub fn with_self_metrics(mut self, namespace: &str) -> Self {
+        self.self_metrics = Some(namespace.to_owned());
+        self
+    }
+
+    /// Makes `add` reject metrics that lack any of the given label keys with
+    /// [`AddError::MissingRequiredLabel`].
+    pub fn with_required_labels(mut self, keys: &[&str]) -> Self {
+        self.required_labels = keys.iter().map(|key| key.to_string()).collect();
+        self
+    }
+
+    /// Pre-epoch timestamps are written as negative milliseconds by default,
+    /// which Victoria Metrics may reject. When enabled, they are clamped to
+    /// the epoch instead.
+    pub fn with_clamp_pre_epoch(mut self, clamp: bool) -> Self {
+        self.clamp_pre_epoch = clamp;
+        self
+    }
+
+    /// Registers a transform that `add` applies to every metric before it is
+    /// buffered. The transform may rewrite the metric in place; returning
+    /// `false` drops it. Metrics added with
+    /// [`MetricsWriter::add_json_value`] are not transformed.
+    pub fn with_transform(
+        mut self,
+        transform: impl Fn(&mut MetricData) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.transform = Some(Box::new(transform));
+        self
+    }
+
+    /// Hides the values of label `key` behind `***` in diagnostics such as
+    /// error messages. The values are still sent as-is. In messages, a value
+    /// is only hidden where it appears as a whole word, so that e.g. a value
+    /// `1` leaves a status `401` readable. Up to 1024 distinct values are
+    /// remembered between sends; with more, error messages are hidden
+    /// entirely.
+    pub fn redact_label(mut self, key: &str) -> Self {
+        self.redacted_labels.insert(key.to_owned());
+        self
+    }
+
+    /// Makes `add` fail with [`AddError::NonMonotonicTimestamps`] unless the
+    /// timestamps of each call are strictly increasing, to surface clock
+    /// problems at the source. Timestamps are compared after precision is
+    /// applied.
+    pub fn with_monotonic_timestamps(mut self, enabled: bool) -> Self {
+        self.monotonic_timestamps = enabled;
+        self
+    }
+
+    /// Makes `add` fail with [`AddError::CardinalityLimitExceeded`] when a
+    /// metric would bring the number of distinct series buffered since the
+    /// last send above `max`.
+    pub fn with_max_series(mut self, max: usize) -> Self {
+        self.max_series = Some(max);
+        self
+    }
+
+    /// Replaces the clock used to stamp [`MetricsWriter::add_snapshot`]
+    /// samples and self-metrics. Defaults to [`Utc::now`].
+    pub fn with_clock(mut self, clock: impl Fn() -> DateTime<Utc> + Send + Sync + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Calls `callback` after every send with why it happened and whether it
+    /// succeeded.
+    pub fn with_on_flush(
+        mut self,
+        callback: impl Fn(&SendOutcome) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_flush = Some(Box::new(callback));
+        self
+    }
+
+    /// Makes `send` merge the lines buffered for the same series into one,
+    /// keeping only the last value added for each timestamp, e.g. for gauges
+    /// that are re-read periodically. Samples are then sent in timestamp
+    /// order.
+    pub fn with_last_write_wins(mut self, enabled: bool) -> Self {
+        self.last_write_wins = enabled;
+        self
+    }
+
+    /// Caps the distinct series per import request for the default buffer.
+    /// Once `max` series are buffered, a line for another series starts a
+    /// new batch. [`MetricsWriter::add_with_flush`] then sends the full
+    /// batch right away; otherwise [`MetricsWriter::send`] posts each batch
+    /// in its own request.
+    pub fn with_max_series_per_flush(mut self, max: usize) -> Self {
+        self.max_series_per_flush = Some(max.max(1));
+        self
+    }
+
+    /// Makes `add` and its variants rewrite metric and label names to valid
+    /// Prometheus names instead of writing them as given: invalid characters
+    /// become `_`, e.g. `my.metric-1` becomes `my_metric_1`, and a leading
+    /// digit is prefixed with `_`. Label keys that collide after rewriting
+    /// are handled by the duplicate label policy. See
+    /// [`MetricsWriter::add_json_value`] for metrics given as JSON.
+    pub fn with_name_normalization(mut self, enabled: bool) -> Self {
+        self.normalize_names = enabled;
+        self
+    }
+
+    /// Sets how `add` handles a label key given more than once. Defaults to
+    /// [`DuplicateLabelPolicy::Error`].
+    pub fn with_duplicate_labels(mut self, policy: DuplicateLabelPolicy) -> Self {
+        self.duplicate_labels = policy;
+        self
+    }
+
+    /// Makes `add` fail with [`AddError::ValueTypeConflict`] when a series
+    /// name receives both integer and float values within one batch.
+    pub fn with_strict_value_types(mut self, strict: bool) -> Self {
+        self.strict_value_types = strict;
+        self
+    }
+
+    pub fn stats(&self) -> WriterStats {
+        self.stats
+    }
+
+    /// Whether nothing is buffered, including pending snapshot samples.
+    fn is_empty(&self) -> bool {
+        self.buffer.is_empty() && self.tenants.is_empty() && self.snapshots.is_empty()
+    }
+
+    /// Returns the number of bytes and lines currently buffered.
+    pub fn buffer_stats(&self) -> BufferStats {
+        std::iter::once(&self.buffer)
+            .chain(self.tenants.values())
+            .fold(BufferStats::default(), |stats, buffer| BufferStats {
+                bytes: stats.bytes + buffer.len(),
+                lines: stats.lines + buffer.lines(),
+            })
+    }
+
+    /// Like [`MetricsWriter::add`], then calls `flush_if` with the resulting
+    /// [`BufferStats`] and sends the buffer if it returns `true`. Returns
+    /// whether the buffer was sent.
+    ///
+    /// With [`MetricsWriter::with_max_series_per_flush`], batches that are
+    /// full are sent right away, whatever `flush_if` returns.
+    pub async fn add_with_flush<T, L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: &[DateTime<Utc>],
+        flush_if: impl FnOnce(BufferStats) -> bool,
+    ) -> Result<bool, FlushError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        self.add(name, labels, values, timestamps)?;
+        let sent_full = self.buffer.has_sealed();
+        if sent_full {
+            self.send_full_batches().await?;
+        }
+        if !flush_if(self.buffer_stats()) {
+            return Ok(sent_full);
+        }
+        self.send_with_reason(FlushReason::Threshold).await?;
+        Ok(true)
+    }
+
+    /// Buffers `values` sampled at `timestamps` for the series `name` with
+    /// `labels`.
+    ///
+    /// Values are written with `serde_json`, so integer types are written
+    /// without a decimal point (`0`) and floating point types always with
+    /// one (`0.0`, `1.5`). NaN and infinite values cannot be written as JSON
+    /// numbers and fail with [`AddError::NonFiniteValue`].
+    pub fn add<T, L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        self.add_with_precision(name, labels, values, timestamps, self.precision)
+    }
+
+    /// Buffers a sample without a timestamp. On the next
+    /// [`MetricsWriter::send`], all such samples are stamped with one
+    /// timestamp taken from the writer's clock (see
+    /// [`MetricsWriter::with_clock`]), giving a consistent point-in-time
+    /// snapshot. Pending snapshot samples are not part of
+    /// [`MetricsWriter::payload`] until then.
+    pub fn add_snapshot<T, L>(&mut self, name: &str, labels: &L, value: T) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        // Normalize first, as in `add`, so keys that only collide once
+        // normalized are caught by the duplicate label policy.
+        if self.normalize_names {
+            let name = normalize_name(name, true);
+            self.add_snapshot_normalized(&name, &NormalizedLabels(labels), value)
+        } else {
+            self.add_snapshot_normalized(name, labels, value)
+        }
+    }
+
+    fn add_snapshot_normalized<T, L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        value: T,
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        let values = [value];
+        let no_timestamps = Timestamps::Millis(&[]);
+        let mut metric = match self.unique_labels(labels)? {
+            Some(labels) => MetricData::new(name, &labels, &values, no_timestamps),
+            None => MetricData::new(name, labels, &values, no_timestamps),
+        };
+        if let Some(transform) = &self.transform {
+            if !transform(&mut metric) {
+                return Ok(());
+            }
+        }
+        self.check_metric(
+            None,
+            &metric.name,
+            &metric.labels,
+            &metric.values,
+            no_timestamps,
+        )?;
+        self.snapshots.push(metric);
+        Ok(())
+    }
+
+    /// Adds samples of a monotonically increasing counter. Currently the same
+    /// as [`MetricsWriter::add`]; the JSON import format carries no metric
+    /// type.
+    pub fn add_counter<T, L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        self.add(name, labels, values, timestamps)
+    }
+
+    /// Adds samples of a gauge. Currently the same as [`MetricsWriter::add`].
+    pub fn add_gauge<T, L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        self.add(name, labels, values, timestamps)
+    }
+
+    /// Like [`MetricsWriter::add`], but overrides the writer's default
+    /// timestamp precision for this call only.
+    pub fn add_with_precision<T, L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: &[DateTime<Utc>],
+        precision: TimestampPrecision,
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        let timestamps = self.timestamps(timestamps, precision);
+        self.add_raw(None, name, labels, values, timestamps)
+    }
+
+    /// Like [`MetricsWriter::add`], but takes timestamps as epoch
+    /// milliseconds, which are written unchanged; the writer's precision and
+    /// pre-epoch clamping do not apply.
+    pub fn add_millis<T, L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps_ms: &[i64],
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        self.add_raw(
+            None,
+            name,
+            labels,
+            values,
+            Timestamps::Millis(timestamps_ms),
+        )
+    }
+
+    /// Like [`MetricsWriter::add`], but buffers the metric for a Victoria
+    /// Metrics cluster tenant, given as `accountID` or `accountID:projectID`.
+    /// On [`MetricsWriter::send`] each tenant's metrics are posted in a
+    /// separate request to `/insert/<tenant>/prometheus/api/v1/import` on
+    /// the writer's host, which should then be a `vminsert` instance.
+    ///
+    /// Fails with [`AddError::InvalidTenant`] if `tenant` has neither form.
+    pub fn add_for_tenant<T, L>(
+        &mut self,
+        tenant: &str,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        if !import_url::is_valid_tenant(tenant) {
+            return Err(AddError::InvalidTenant(tenant.to_owned()));
+        }
+        let timestamps = self.timestamps(timestamps, self.precision);
+        self.add_raw(Some(tenant), name, labels, values, timestamps)
+    }
+
+    fn timestamps<'a>(
+        &self,
+        timestamps: &'a [DateTime<Utc>],
+        precision: TimestampPrecision,
+    ) -> Timestamps<'a> {
+        Timestamps::DateTimes {
+            timestamps,
+            precision,
+            clamp_pre_epoch: self.clamp_pre_epoch,
+        }
+    }
+
+    fn add_raw<T, L>(
+        &mut self,
+        tenant: Option<&str>,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: Timestamps,
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        if self.normalize_names {
+            let name = normalize_name(name, true);
+            let labels = NormalizedLabels(labels);
+            self.add_deduplicated(tenant, &name, &labels, values, timestamps)
+        } else {
+            self.add_deduplicated(tenant, name, labels, values, timestamps)
+        }
+    }
+
+    fn add_deduplicated<T, L>(
+        &mut self,
+        tenant: Option<&str>,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: Timestamps,
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        match self.unique_labels(labels)? {
+            Some(labels) => self.add_unique(tenant, name, &labels, values, timestamps),
+            None => self.add_unique(tenant, name, labels, values, timestamps),
+        }
+    }
+
+    /// Applies the duplicate label policy, returning `None` if `labels` has
+    /// no duplicate keys.
+    fn unique_labels<'a, L>(&self, labels: &'a L) -> Result<Option<UniqueLabels<'a, L>>, AddError>
+    where
+        L: Labels + ?Sized,
+    {
+        let key = match first_duplicate_label(labels) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let keep_last = match self.duplicate_labels {
+            DuplicateLabelPolicy::Error => return Err(AddError::DuplicateLabel(key)),
+            DuplicateLabelPolicy::KeepFirst => false,
+            DuplicateLabelPolicy::KeepLast => true,
+        };
+        Ok(Some(UniqueLabels::new(labels, keep_last)))
+    }
+
+    fn add_unique<T, L>(
+        &mut self,
+        tenant: Option<&str>,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: Timestamps,
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        if let Some(transform) = &self.transform {
+            let mut metric = MetricData::new(name, labels, values, timestamps);
+            if !transform(&mut metric) {
+                return Ok(());
+            }
+            self.check_metric(
+                tenant,
+                &metric.name,
+                &metric.labels,
+                &metric.values,
+                Timestamps::Millis(&metric.timestamps),
+            )?;
+            self.write_metric(
+                tenant,
+                &metric.name,
+                &metric.labels,
+                &metric.values,
+                Timestamps::Millis(&metric.timestamps),
+            );
+        } else {
+            self.check_metric(tenant, name, labels, values, timestamps)?;
+            self.write_metric(tenant, name, labels, values, timestamps);
+        }
+        Ok(())
+    }
+
+    fn check_metric<T, L>(
+        &mut self,
+        tenant: Option<&str>,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: Timestamps,
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        // serde_json writes NaN and infinite floats as `null`.
+        if values
+            .iter()
+            .any(|value| value_kind(value) == Err(KindError::NonFinite))
+        {
+            return Err(AddError::NonFiniteValue(name.to_owned()));
+        }
+        self.check_monotonic(timestamps)?;
+        self.check_required_labels(|f| labels.for_each_label(&mut |key, _| f(key)))?;
+        let key = self.check_max_series(|| series_key(tenant, name, labels))?;
+        if self.strict_value_types {
+            self.check_value_types(name, values)?;
+        }
+        if let Some(key) = key {
+            if self.undo.is_some() && !self.series.contains(&key) {
+                self.record(Undo::Series(key.clone()));
+            }
+            self.series.insert(key);
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, change: Undo) {
+        if let Some(undo) = &mut self.undo {
+            undo.push(change);
+        }
+    }
+
+    /// Runs `add`, which may add several lines to the default buffer, and
+    /// removes all of them again if it fails, so that e.g. a histogram is
+    /// never buffered without its `_sum`.
+    fn add_all_or_nothing(
+        &mut self,
+        add: impl FnOnce(&mut Self) -> Result<(), AddError>,
+    ) -> Result<(), AddError> {
+        let checkpoint = self.buffer.checkpoint();
+        self.undo = Some(Vec::new());
+        let result = add(self);
+        let undo = self.undo.take().unwrap_or_default();
+        if result.is_err() {
+            self.buffer.rollback(checkpoint);
+            for change in undo.into_iter().rev() {
+                match change {
+                    Undo::Series(key) => {
+                        self.series.remove(&key);
+                    }
+                    Undo::BatchSeries(key) => {
+                        self.batch_series.remove(&key);
+                    }
+                    Undo::Sealed(series) => self.batch_series = series,
+                    Undo::ValueType(name, Some(kind)) => {
+                        self.value_types.insert(name, kind);
+                    }
+                    Undo::ValueType(name, None) => {
+                        self.value_types.remove(&name);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn check_monotonic(&self, timestamps: Timestamps) -> Result<(), AddError> {
+        if self.monotonic_timestamps
+            && timestamps
+                .iter()
+                .zip(timestamps.iter().skip(1))
+                .any(|(a, b)| a >= b)
+        {
+            return Err(AddError::NonMonotonicTimestamps);
+        }
+        Ok(())
+    }
+
+    /// Fails if a cardinality cap is set and the series built by `key` would
+    /// exceed it. Returns the key to record once all other checks pass.
+    fn check_max_series(
+        &self,
+        key: impl FnOnce() -> SeriesKey,
+    ) -> Result<Option<SeriesKey>, AddError> {
+        let max = match self.max_series {
+            Some(max) => max,
+            None => return Ok(None),
+        };
+        let key = key();
+        if !self.series.contains(&key) && self.series.len() >= max {
+            return Err(AddError::CardinalityLimitExceeded(max));
+        }
+        Ok(Some(key))
+    }
+
+    /// Records the numeric type of `values` for series `name`, failing if it
+    /// differs from what was buffered for that name earlier in the batch.
+    fn check_value_types<T: serde::Serialize>(
+        &mut self,
+        name: &str,
+        values: &[T],
+    ) -> Result<(), AddError> {
+        let mut kind = self.value_types.get(name).copied();
+        for value in values {
+            let value_kind = match value_kind(value) {
+                Ok(Some(kind)) => kind,
+                _ => continue,
+            };
+            match kind {
+                Some(kind) if kind != value_kind => {
+                    return Err(AddError::ValueTypeConflict(name.to_owned()))
+                }
+                _ => kind = Some(value_kind),
+            }
+        }
+        if let Some(kind) = kind {
+            let previous = self.value_types.insert(name.to_owned(), kind);
+            if previous != Some(kind) {
+                self.record(Undo::ValueType(name.to_owned(), previous));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_required_labels(
+        &self,
+        for_each_key: impl FnOnce(&mut dyn FnMut(&str)),
+    ) -> Result<(), AddError> {
+        if self.required_labels.is_empty() {
+            return Ok(());
+        }
+
+        let mut found = vec![false; self.required_labels.len()];
+        for_each_key(&mut |key| {
+            if let Some(i) = self.required_labels.iter().position(|k| k == key) {
+                found[i] = true;
+            }
+        });
+        match found.iter().position(|found| !found) {
+            Some(i) => Err(AddError::MissingRequiredLabel(
+                self.required_labels[i].clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn write_metric<T, L>(
+        &mut self,
+        tenant: Option<&str>,
+        name: &str,
+        labels: &L,
+        values: &[T],
+        timestamps: Timestamps,
+    ) where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        if !self.redacted_labels.is_empty() {
+            labels.for_each_label(&mut |key, value| self.remember_redacted(key, value));
+        }
+
+        let metric = Metric {
+            meta: MetricMeta { name, labels },
+            timestamps,
+            values,
+        };
+        self.write_line(
+            tenant,
+            || series_key(None, name, labels),
+            values.len(),
+            |buffer| serde_json::to_writer(buffer, &metric).unwrap(),
+        );
+    }
+
+    /// Appends a line for the series `key` with `samples` samples to the
+    /// buffer of `tenant`, or to the default buffer, where a per-flush series
+    /// cap may first start a new batch. All lines but those of a
+    /// [`BufferWriter`] are written through here.
+    fn write_line(
+        &mut self,
+        tenant: Option<&str>,
+        key: impl FnOnce() -> SeriesKey,
+        samples: usize,
+        write: impl FnOnce(&mut Vec<u8>),
+    ) {
+        let buffer = match tenant {
+            Some(tenant) => self.tenants.entry(tenant.to_owned()).or_default(),
+            None => {
+                self.seal_if_full(key, self.buffer.len());
+                &mut self.buffer
+            }
+        };
+        buffer.write_line(samples, write);
+    }
+
+    /// Appends bytes from a [`BufferWriter`], then applies the per-flush
+    /// series cap to every line they complete and counts their samples for
+    /// the rate limit. Lines are only parsed if either is configured.
+    pub(crate) fn write_raw(&mut self, bytes: &[u8]) {
+        self.buffer.append(bytes);
+        while let Some(line) = self.buffer.next_line() {
+            if self.max_series_per_flush.is_none() && self.rate_limiter.is_none() {
+                continue;
+            }
+            let start = line.start;
+            if let Ok(metric) = serde_json::from_slice::<BufferedMetric>(&self.buffer.bytes()[line])
+            {
+                let samples = metric.values.len();
+                self.seal_if_full(|| (None, metric.metric), start);
+                self.buffer.add_samples(samples);
+            }
+        }
+    }
+
+    /// With a per-flush series cap, ends the default buffer's open batch at
+    /// `line_start` if the line starting there is for a new series that
+    /// would exceed the cap.
+    fn seal_if_full(&mut self, key: impl FnOnce() -> SeriesKey, line_start: usize) {
+        let max = match self.max_series_per_flush {
+            Some(max) => max,
+            None => return,
+        };
+        let key = key();
+        if self.batch_series.contains(&key) {
+            return;
+        }
+        if self.batch_series.len() >= max {
+            self.buffer.seal_at(line_start);
+            let series = std::mem::take(&mut self.batch_series);
+            self.record(Undo::Sealed(series));
+        }
+        if self.undo.is_some() {
+            self.record(Undo::BatchSeries(key.clone()));
+        }
+        self.batch_series.insert(key);
+    }
+
+    /// Adds a Prometheus-style histogram as `<name>_bucket` series with `le`
+    /// labels, plus `<name>_sum` and `<name>_count`. `buckets` are
+    /// `(upper bound, cumulative count)` pairs in increasing order; a `+Inf`
+    /// bucket holding `count` is added unless the last bound is already
+    /// infinite.
+    pub fn add_histogram<L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        buckets: &[(f64, u64)],
+        sum: f64,
+        count: u64,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AddError>
+    where
+        L: Labels + ?Sized,
+    {
+        let timestamps = [timestamp];
+        let bucket_name = format!("{}_bucket", name);
+        let has_inf = buckets.last().is_some_and(|(le, _)| le.is_infinite());
+        let inf = (!has_inf).then_some((f64::INFINITY, count));
+        self.add_all_or_nothing(|writer| {
+            for (le, bucket_count) in buckets.iter().copied().chain(inf) {
+                let le = float_label(le);
+                let labels = WithLabel {
+                    labels,
+                    key: "le",
+                    value: &le,
+                };
+                writer.add(&bucket_name, &labels, &[bucket_count], &timestamps)?;
+            }
+            writer.add(&format!("{}_sum", name), labels, &[sum], &timestamps)?;
+            writer.add(&format!("{}_count", name), labels, &[count], &timestamps)
+        })
+    }
+
+    /// Adds a Prometheus-style summary as `<name>` series with `quantile`
+    /// labels, plus `<name>_sum` and `<name>_count`. `quantiles` are
+    /// `(quantile, value)` pairs, e.g. `(0.99, 0.27)`.
+    pub fn add_summary<L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        quantiles: &[(f64, f64)],
+        sum: f64,
+        count: u64,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), AddError>
+    where
+        L: Labels + ?Sized,
+    {
+        let timestamps = [timestamp];
+        self.add_all_or_nothing(|writer| {
+            for &(quantile, value) in quantiles {
+                let quantile = float_label(quantile);
+                let labels = WithLabel {
+                    labels,
+                    key: "quantile",
+                    value: &quantile,
+                };
+                writer.add(name, &labels, &[value], &timestamps)?;
+            }
+            writer.add(&format!("{}_sum", name), labels, &[sum], &timestamps)?;
+            writer.add(&format!("{}_count", name), labels, &[count], &timestamps)
+        })
+    }
+
+    /// Like [`MetricsWriter::add`], but records the series' expected sample
+    /// interval. The JSON import format has no field for this, so the hint is
+    /// encoded as a `scrape_interval` label holding a Prometheus duration,
+    /// e.g. `scrape_interval="15s"`, or `"1500ms"` for sub-second intervals.
+    /// Since it is a label, it becomes part of the series identity.
+    pub fn add_with_interval<T, L>(
+        &mut self,
+        name: &str,
+        labels: &L,
+        interval: Duration,
+        values: &[T],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<(), AddError>
+    where
+        T: serde::Serialize,
+        L: Labels + ?Sized,
+    {
+        let interval = interval_label(interval);
+        let labels = WithLabel {
+            labels,
+            key: "scrape_interval",
+            value: &interval,
+        };
+        self.add(name, &labels, values, timestamps)
+    }
+
+    /// Returns an [`AsyncWrite`](tokio::io::AsyncWrite) handle that appends
+    /// pre-formatted JSON lines to the default buffer, for streaming data in
+    /// from async pipelines. The lines are sent by the next
+    /// [`MetricsWriter::send`]; see [`BufferWriter`] for the caveats.
+    pub fn buffer_writer(&mut self) -> BufferWriter<'_> {
+        BufferWriter { writer: self }
+    }
+
+    /// Buffers a metric that has already been assembled as a JSON value in
+    /// Victoria Metrics' import format, i.e. an object with a `metric` object
+    /// holding `__name__` and string labels, and equally long `values` and
+    /// `timestamps` arrays.
+    ///
+    /// With [`MetricsWriter::with_name_normalization`], `__name__` is
+    /// normalized like the name given to `add`, but label keys are written
+    /// as given.
+    pub fn add_json_value(&mut self, metric: &serde_json::Value) -> Result<(), AddError> {
+        validate_json_metric(metric)?;
+        let metric = &*self.normalize_json_name(metric);
+        let timestamps: Vec<i64> = metric["timestamps"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|ts| ts.as_i64())
+            .collect();
+        self.check_monotonic(Timestamps::Millis(&timestamps))?;
+        self.check_required_labels(|f| {
+            metric["metric"]
+                .as_object()
+                .into_iter()
+                .flat_map(|meta| meta.keys())
+                .for_each(|key| f(key))
+        })?;
+        let key = self.check_max_series(|| json_series_key(None, metric))?;
+        if let Some(key) = key {
+            self.series.insert(key);
+        }
+        if let Some(meta) = metric["metric"].as_object() {
+            for (key, value) in meta {
+                self.remember_redacted(key, value.as_str().unwrap_or_default());
+            }
+        }
+
+        let samples = metric["values"].as_array().map_or(0, Vec::len);
+        self.write_line(
+            None,
+            || json_series_key(None, metric),
+            samples,
+            |buffer| serde_json::to_writer(buffer, metric).unwrap(),
+        );
+        Ok(())
+    }
+
+    fn normalize_json_name<'a>(&self, metric: &'a serde_json::Value) -> Cow<'a, serde_json::Value> {
+        let name = match metric["metric"]["__name__"].as_str() {
+            Some(name) if self.normalize_names => normalize_name(name, true),
+            _ => return Cow::Borrowed(metric),
+        };
+        match name {
+            Cow::Borrowed(_) => Cow::Borrowed(metric),
+            Cow::Owned(name) => {
+                let mut metric = metric.clone();
+                metric["metric"]["__name__"] = name.into();
+                Cow::Owned(metric)
+            }
+        }
+    }
+
+    fn remember_redacted(&mut self, key: &str, value: &str) {
+        if !value.is_empty() && self.redacted_labels.contains(key) {
+            self.redacted_values.insert(value);
+        }
+    }
+
+    pub async fn send(&mut self) -> Result<(), SendError> {
+        self.send_with_reason(FlushReason::Manual).await
+    }
+
+    /// Like [`MetricsWriter::send`], but reports `reason` to the
+    /// [`MetricsWriter::with_on_flush`] callback, e.g.
+    /// [`FlushReason::Shutdown`] for the last send before exiting.
+    pub async fn send_with_reason(&mut self, reason: FlushReason) -> Result<(), SendError> {
+        let lines = self.buffer_stats().lines + self.snapshots.len();
+        let result = self.send_buffers(None).await;
+        self.record_outcome(reason, lines, &result);
+        result
+    }
+
+    /// Like [`MetricsWriter::send`], but on success returns the lines that
+    /// were delivered, or `None` if nothing was buffered, e.g. to archive
+    /// them only once the server has accepted them. With tenants or a
+    /// series cap the lines of every request are concatenated.
+    pub async fn send_returning(&mut self) -> Result<Option<Bytes>, SendError> {
+        let lines = self.buffer_stats().lines + self.snapshots.len();
+        let mut delivered = Vec::new();
+        let result = self.send_buffers(Some(&mut delivered)).await;
+        self.record_outcome(FlushReason::Manual, lines, &result);
+        result.map(|()| (!delivered.is_empty()).then(|| Bytes::from(delivered)))
+    }
+
+    fn record_outcome(
+        &mut self,
+        reason: FlushReason,
+        lines: usize,
+        result: &Result<(), SendError>,
+    ) {
+        self.last_send_ok = Some(result.is_ok());
+        if let Some(callback) = &self.on_flush {
+            callback(&SendOutcome {
+                reason,
+                lines,
+                success: result.is_ok(),
+            });
+        }
+    }
+
+    /// Returns whether the most recent [`MetricsWriter::send`] succeeded, or
+    /// `None` if nothing has been sent yet.
+    pub fn last_send_ok(&self) -> Option<bool> {
+        self.last_send_ok
+    }
+
+    async fn send_buffers(&mut self, mut delivered: Option<&mut Vec<u8>>) -> Result<(), SendError> {
+        self.check_circuit()?;
+
+        if self.is_empty() {
+            return Ok(());
+        }
+        if let Some(namespace) = self.self_metrics.take() {
+            self.add_self_metrics(&namespace);
+            self.self_metrics = Some(namespace);
+        }
+        self.stamp_snapshots();
+
+        // Everything is taken out of the writer before the first request, so
+        // that a send cancelled halfway, e.g. by `send_with_deadline`, leaves
+        // an empty buffer rather than a partly sent one.
+        let default_batches = self.buffer.take_batches();
+        let tenants = std::mem::take(&mut self.tenants);
+        let redacted = std::mem::take(&mut self.redacted_values);
+        self.batch_series.clear();
+        self.value_types.clear();
+        self.series.clear();
+
+        // Every buffer is attempted even if an earlier request fails; the
+        // first error is returned.
+        let mut result = Ok(());
+        for (body, samples) in default_batches {
+            let sent = self
+                .send_batch(None, body, samples, &redacted, delivered.as_deref_mut())
+                .await;
+            result = result.and(sent);
+        }
+        for (tenant, mut buffer) in tenants {
+            for (body, samples) in buffer.take_batches() {
+                let sent = self
+                    .send_batch(
+                        Some(&tenant),
+                        body,
+                        samples,
+                        &redacted,
+                        delivered.as_deref_mut(),
+                    )
+                    .await;
+                result = result.and(sent);
+            }
+        }
+        result
+    }
+
+    /// Sends the default buffer's batches that a per-flush series cap has
+    /// closed, keeping the open one buffered.
+    async fn send_full_batches(&mut self) -> Result<(), SendError> {
+        self.check_circuit()?;
+
+        let batches = self.buffer.take_sealed();
+        let lines = batches
+            .iter()
+            .map(|(body, _)| body.iter().filter(|b| **b == b'\n').count())
+            .sum();
+        // The open batch may still hold values to hide.
+        let redacted = self.redacted_values.clone();
+        self.rebuild_series();
+
+        let mut result = Ok(());
+        for (body, samples) in batches {
+            let sent = self.send_batch(None, body, samples, &redacted, None).await;
+            result = result.and(sent);
+        }
+        self.record_outcome(FlushReason::Threshold, lines, &result);
+        result
+    }
+
+    /// Streams the series matching `selector`, e.g. `{job="api"}`, from the
+    /// writer's instance through `/api/v1/export`, optionally limited to
+    /// samples between `start` and `end`. Each item can be re-imported, e.g.
+    /// into another instance, with [`MetricsWriter::add_millis`]. With a
+    /// tenant configured, the series are read from `vmselect`'s tenant path
+    /// on the same host.
+    pub fn export(
+        &self,
+        selector: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> impl Stream<Item = Result<MetricData, SendError>> {
+        let rfc3339 = |ts: DateTime<Utc>| ts.to_rfc3339_opts(SecondsFormat::Millis, true);
+        let url = import_url::build_export_url(
+            &self.url_config,
+            selector,
+            start.map(rfc3339).as_deref(),
+            end.map(rfc3339).as_deref(),
+        );
+        let mut request = self.client.get(url);
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        export::export_stream(request, self.error_parser.clone())
+    }
+
+    /// Posts a file of JSON lines in Victoria Metrics' import format, e.g.
+    /// one written from [`MetricsWriter::payload`], using the writer's URL
+    /// and compression settings. The writer's buffer is left untouched.
+    pub async fn replay_file(&mut self, path: impl AsRef<Path>) -> Result<(), SendError> {
+        self.check_circuit()?;
+
+        let mut body = tokio::fs::read(path).await?;
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+        if !body.ends_with(b"\n") {
+            body.extend_from_slice(b"\r\n");
+        }
+        let samples = match self.rate_limiter {
+            Some(_) => buffer::count_samples(&body),
+            None => 0,
+        };
+        let result = self.send_body(None, body, samples).await;
+        result.map_err(|err| redact_error(err, &self.redacted_values))
+    }
+
+    fn check_circuit(&self) -> Result<(), SendError> {
+        match &self.circuit_breaker {
+            Some(breaker) if breaker.is_open() => Err(SendError::CircuitOpen),
+            _ => Ok(()),
+        }
+    }
+
+    async fn send_batch(
+        &mut self,
+        tenant: Option<&str>,
+        body: Vec<u8>,
+        samples: usize,
+        redacted: &RedactedValues,
+        delivered: Option<&mut Vec<u8>>,
+    ) -> Result<(), SendError> {
+        // Merging only ever drops samples, so `samples` stays an upper bound
+        // for the rate limit.
+        let body = if self.last_write_wins {
+            dedup::last_write_wins(&body)
+        } else {
+            body
+        };
+        let result = match delivered {
+            Some(delivered) => {
+                let result = self.send_body(tenant, body.clone(), samples).await;
+                if result.is_ok() {
+                    delivered.extend_from_slice(&body);
+                }
+                result
+            }
+            None => self.send_body(tenant, body, samples).await,
+        };
+        result.map_err(|err| redact_error(err, redacted))
+    }
+
+    /// Returns the import URL for `tenant`, or for the configured one if
+    /// `None`, checking it the first time it is built.
+    fn import_url(&mut self, tenant: Option<&str>) -> Result<String, SendError> {
+        let key = tenant.map(str::to_owned);
+        if let Some(url) = self.import_urls.get(&key) {
+            return Ok(url.clone());
+        }
+        let url = match tenant {
+            Some(tenant) => import_url::checked_import_url(&ImportUrlConfig {
+                tenant: Some(tenant.to_owned()),
+                ..self.url_config.clone()
+            }),
+            None => import_url::checked_import_url(&self.url_config),
+        }
+        .map_err(SendError::InvalidUrl)?;
+        self.import_urls.insert(key, url.clone());
+        Ok(url)
+    }
+
+    /// Posts `body`, which holds `samples` samples, with retries.
+    async fn send_body(
+        &mut self,
+        tenant: Option<&str>,
+        body: Vec<u8>,
+        samples: usize,
+    ) -> Result<(), SendError> {
+        let url = self.import_url(tenant)?;
+        if let Some(limiter) = &mut self.rate_limiter {
+            let delay = limiter.reserve(samples, tokio::time::Instant::now());
+            tokio::time::sleep(delay).await;
+        }
+        let mut gzip = self.gzip.filter(|_| body.len() >= self.gzip_min_size);
+        if gzip.is_some() && self.gzip_detection && !self.probe_gzip(&url).await {
+            gzip = None;
+        }
+        let body = Bytes::from(match gzip {
+            Some(level) => gzip::compress(&body, level),
+            None => body,
+        });
+        let len = body.len() as u64;
+
+        // A request that cannot be built, e.g. because of an invalid bearer
+        // token, fails the same way on every attempt.
+        let result = match self.build_request(&url, body, gzip.is_some()) {
+            Ok(request) => self.post_with_retries(request).await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => self.stats.bytes += len,
+            Err(_) => self.stats.failures += 1,
+        }
+        if let Some(breaker) = &mut self.circuit_breaker {
+            breaker.record(result.is_ok());
+        }
+        result
+    }
+
+    /// Posts `request`, retrying according to the retry policy.
+    async fn post_with_retries(&mut self, request: reqwest::Request) -> Result<(), SendError> {
+        let mut attempt = 0;
+        loop {
+            self.stats.requests += 1;
+            let in_flight = self.in_flight.clone();
+            let permit = match &in_flight {
+                Some(limit) => Some(limit.acquire().await),
+                None => None,
+            };
+            let started = Instant::now();
+            let mut retry_after = None;
+            // The body is in memory, so the request can always be cloned.
+            let request = request.try_clone().unwrap();
+            let result = self.post(request, &mut retry_after).await;
+            self.record_latency(started.elapsed());
+            drop(permit);
+
+            let retryable = match &result {
+                Err(SendError::RequestError(_)) => true,
+                Err(SendError::InvalidResponseStatusCode(status, _)) => {
+                    retry::is_retryable_status(*status)
+                }
+                _ => false,
+            };
+            match &self.retry {
+                Some(policy) if retryable && attempt < policy.max_retries => {
+                    tokio::time::sleep(policy.delay(attempt, retry_after)).await;
+                    attempt += 1;
+                }
+                _ => break result,
+            }
+        }
+    }
+
+    fn record_latency(&mut self, latency: Duration) {
+        if self.latencies.len() == RECENT_LATENCIES {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+        self.stats.latency_min = self.latencies.iter().min().copied();
+        self.stats.latency_max = self.latencies.iter().max().copied();
+        self.stats.latency_avg =
+            Some(self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32);
+    }
+
+    /// Returns whether the server at `url` accepts gzip-compressed requests,
+    /// probing it unless an earlier probe succeeded.
+    async fn probe_gzip(&mut self, url: &str) -> bool {
+        if let Some(supported) = self.gzip_supported {
+            return supported;
+        }
+        let mut request = self.client.request(reqwest::Method::OPTIONS, url);
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(_) => return false,
+        };
+        let supported = response
+            .headers()
+            .get_all(reqwest::header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|encoding| encoding.trim().eq_ignore_ascii_case("gzip"));
+        // An error status says nothing about the import endpoint, so only a
+        // successful probe is trusted to rule gzip out.
+        if supported || response.status().is_success() {
+            self.gzip_supported = Some(supported);
+        }
+        supported
+    }
+
+    /// Posts `body` once, setting `retry_after` to the delay a 429
+    /// response's `Retry-After` header asks for.
+    fn build_request(
+        &self,
+        url: &str,
+        body: Bytes,
+        gzip: bool,
+    ) -> Result<reqwest::Request, SendError> {
+        let mut request = self.client.post(url).body(body);
+        if gzip {
+            request = request.header(reqwest::header::CONTENT_ENCODING, "gzip");
+        }
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        Ok(request.build()?)
+    }
+
+    async fn post(
+        &self,
+        request: reqwest::Request,
+        retry_after: &mut Option<Duration>,
+    ) -> Result<(), SendError> {
+        #[cfg(feature = "middleware")]
+        let response = match &self.middleware_client {
+            Some(client) => client.execute(request).await?,
+            None => self.client.execute(request).await?,
+        };
+        #[cfg(not(feature = "middleware"))]
+        let response = self.client.execute(request).await?;
+
+        let status = response.status();
+        if !status.is_success() && !self.accepted_statuses.contains(&status) {
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                *retry_after = response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| retry::parse_retry_after(value, Utc::now()));
+            }
+            // A body that fails to read, e.g. a corrupt gzip stream, must
+            // not hide the status.
+            let message = match response.bytes().await {
+                Ok(body) => (self.error_parser)(status, &body),
+                Err(err) => format!("<unreadable response body: {}>", err),
+            };
+            return Err(SendError::InvalidResponseStatusCode(status, message));
+        }
+        Ok(())
+    }
+
+    fn stamp_snapshots(&mut self) {
+        if self.snapshots.is_empty() {
+            return;
+        }
+        let now = [(self.clock)()];
+        let timestamps = self.timestamps(&now, self.precision);
+        for metric in std::mem::take(&mut self.snapshots) {
+            self.write_metric(
+                None,
+                &metric.name,
+                &metric.labels,
+                &metric.values,
+                timestamps,
+            );
+        }
+    }
+
+    fn add_self_metrics(&mut self, namespace: &str) {
+        let now = [(self.clock)().timestamp_millis()];
+        let stats = self.stats;
+        for (name, value) in [
+            ("requests_total", stats.requests),
+            ("bytes_total", stats.bytes),
+            ("failures_total", stats.failures),
+        ] {
+            let name = format!("{}_{}", namespace, name);
+            self.write_metric(
+                None,
+                &name,
+                &[] as &[(&str, &str)],
+                &[value],
+                Timestamps::Millis(&now),
+            );
+        }
+    }
+
+    /// Like [`MetricsWriter::send`], but gives up with
+    /// [`SendError::DeadlineExceeded`] once `deadline` has passed. The deadline
+    /// bounds the whole call, including retries, not each individual
+    /// request. When the deadline is hit, all buffered data is dropped, as
+    /// with any other send failure, even the buffers whose requests had not
+    /// started yet.
+    pub async fn send_with_deadline(&mut self, deadline: Instant) -> Result<(), SendError> {
+        let lines = self.buffer_stats().lines + self.snapshots.len();
+        match tokio::time::timeout_at(deadline.into(), self.send()).await {
+            Ok(result) => result,
+            Err(_) => {
+                let result = Err(SendError::DeadlineExceeded);
+                self.record_outcome(FlushReason::Manual, lines, &result);
+                result
+            }
+        }
+    }
+
+    /// Lists the series in the buffer, one entry per buffered line, without
+    /// consuming it. The entries are parsed back out of the buffer, so this
+    /// is meant for debugging rather than the hot path.
+    pub fn buffered_series(&self) -> impl Iterator<Item = SeriesInfo> + '_ {
+        self.buffered_lines().filter_map(|line| {
+            let BufferedMetric {
+                metric: mut labels,
+                values,
+            } = serde_json::from_slice(line).ok()?;
+            Some(SeriesInfo {
+                name: labels.remove("__name__")?,
+                labels,
+                samples: values.len(),
+            })
+        })
+    }
+
+    /// Renders the buffered metrics as a pretty-printed JSON array for
+    /// debugging, without consuming the buffer. Values of labels registered
+    /// with [`MetricsWriter::redact_label`] are shown as `***`.
+    pub fn debug_dump(&self) -> String {
+        let metrics: Vec<serde_json::Value> = self
+            .buffered_lines()
+            .filter_map(|line| serde_json::from_slice(line).ok())
+            .map(|mut metric: serde_json::Value| {
+                if let Some(meta) = metric["metric"].as_object_mut() {
+                    for (key, value) in meta.iter_mut() {
+                        if self.redacted_labels.contains(key) {
+                            *value = "***".into();
+                        }
+                    }
+                }
+                metric
+            })
+            .collect();
+        serde_json::to_string_pretty(&metrics).unwrap()
+    }
+
+    /// Counts the distinct series (tenant, name and label set) currently
+    /// buffered, no matter how many lines they were added in.
+    pub fn distinct_series(&self) -> usize {
+        self.buffered_lines_by_tenant()
+            .filter_map(|(tenant, line)| {
+                let metric = serde_json::from_slice(line).ok()?;
+                Some(json_series_key(tenant, &metric))
+            })
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+
+    fn buffered_lines(&self) -> impl Iterator<Item = &[u8]> {
+        self.buffered_lines_by_tenant().map(|(_, line)| line)
+    }
+
+    fn buffered_lines_by_tenant(&self) -> impl Iterator<Item = (Option<&str>, &[u8])> {
+        std::iter::once((None, &self.buffer))
+            .chain(
+                self.tenants
+                    .iter()
+                    .map(|(tenant, buffer)| (Some(tenant.as_str()), buffer)),
+            )
+            .flat_map(|(tenant, buffer)| {
+                buffer
+                    .bytes()
+                    .split(|b| *b == b'\n')
+                    .map(move |line| (tenant, line))
+            })
+            .filter(|(_, line)| !line.trim_ascii().is_empty())
+    }
+
+    /// Returns the default buffer's contents without consuming them, or
+    /// `None` if nothing is buffered. Tenant buffers are not included.
+    pub fn payload(&self) -> Option<&[u8]> {
+        (!self.buffer.is_empty()).then(|| self.buffer.bytes())
+    }
+
+    /// Like [`MetricsWriter::payload`], but as a string.
+    pub fn payload_string(&self) -> Result<Option<String>, Utf8Error> {
+        self.payload()
+            .map(|payload| std::str::from_utf8(payload).map(str::to_owned))
+            .transpose()
+    }
+
+    /// Removes and returns complete lines from the front of the default
+    /// buffer, totalling at most `bytes` unless the first line alone is
+    /// larger, in which case just that line is returned. Returns `None` if
+    /// no complete line is buffered, e.g. while a [`BufferWriter`] is part
+    /// way through one. Tenant buffers are not affected.
+    pub fn drain_up_to(&mut self, bytes: usize) -> Option<Bytes> {
+        let buffer = self.payload()?;
+        let mut line_ends = buffer
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b == b'\n')
+            .map(|(i, _)| i + 1);
+        let first = line_ends.next()?;
+        let end = line_ends
+            .take_while(|end| *end <= bytes)
+            .last()
+            .unwrap_or(first);
+
+        // Sample counts are only needed for the rate limit, without one they
+        // may stay too high.
+        let drained = self.buffer.drain_front(end, self.rate_limiter.is_some());
+        self.rebuild_series();
+        Some(Bytes::from(drained))
+    }
+
+    /// Recounts the series tracked for the series caps after some lines
+    /// left the buffer without a full send.
+    fn rebuild_series(&mut self) {
+        if self.max_series.is_some() {
+            self.series = self
+                .buffered_lines_by_tenant()
+                .filter_map(|(tenant, line)| {
+                    let metric = serde_json::from_slice(line).ok()?;
+                    Some(json_series_key(tenant, &metric))
+                })
+                .collect();
+        }
+        if self.max_series_per_flush.is_some() {
+            self.batch_series = self
+                .buffer
+                .open_batch()
+                .split(|b| *b == b'\n')
+                .filter_map(|line| {
+                    let metric = serde_json::from_slice(line).ok()?;
+                    Some(json_series_key(None, &metric))
+                })
+                .collect();
+        }
+    }
+
+    /// Returns the CRC32 checksum of [`MetricsWriter::payload`], e.g. to
+    /// verify a payload written to a file before replaying it.
+    pub fn payload_checksum(&self) -> Option<u32> {
+        self.payload().map(|payload| {
+            let mut crc = flate2::Crc::new();
+            crc.update(payload);
+            crc.sum()
+        })
+    }
+}
+
+/// Hides the remembered values of redacted labels in an error response's
+/// message.
+fn redact_error(err: SendError, values: &RedactedValues) -> SendError {
+    match err {
+        SendError::InvalidResponseStatusCode(status, message) => {
+            SendError::InvalidResponseStatusCode(status, values.redact(&message))
+        }
+        err => err,
+    }
+}
+
+/// Formats a float label value the way Prometheus clients do for `le` and
+/// `quantile`, e.g. `1` rather than `1.0` and `+Inf` for infinity.
+fn float_label(value: f64) -> String {
+    match value {
+        f64::INFINITY => "+Inf".to_owned(),
+        f64::NEG_INFINITY => "-Inf".to_owned(),
+        value => value.to_string(),
+    }
+}
+
+/// Formats a scrape interval as a Prometheus duration, e.g. `15s`, falling
+/// back to milliseconds for sub-second precision, e.g. `1500ms`.
+fn interval_label(interval: Duration) -> String {
+    if interval.subsec_millis() == 0 {
+        format!("{}s", interval.as_secs())
+    } else {
+        format!("{}ms", interval.as_millis())
+    }
+}
+
+fn validate_json_metric(metric: &serde_json::Value) -> Result<(), AddError> {
+    let meta = metric
+        .get("metric")
+        .and_then(|meta| meta.as_object())
+        .ok_or(AddError::InvalidShape("`metric` must be an object"))?;
+    if !meta.get("__name__").is_some_and(|name| name.is_string()) {
+        return Err(AddError::InvalidShape("`metric.__name__` must be a string"));
+    }
+    if !meta.values().all(|label| label.is_string()) {
+        return Err(AddError::InvalidShape("label values must be strings"));
+    }
+
+    let values = metric
+        .get("values")
+        .and_then(|values| values.as_array())
+        .ok_or(AddError::InvalidShape("`values` must be an array"))?;
+    let timestamps = metric
+        .get("timestamps")
+        .and_then(|timestamps| timestamps.as_array())
+        .ok_or(AddError::InvalidShape("`timestamps` must be an array"))?;
+    if !timestamps.iter().all(|ts| ts.is_i64()) {
+        return Err(AddError::InvalidShape("timestamps must be integers"));
+    }
+    if values.len() != timestamps.len() {
+        return Err(AddError::InvalidShape(
+            "`values` and `timestamps` must have the same length",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use futures_util::TryStreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    use chrono::TimeZone;
+    use wiremock::{
+        matchers::{header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    async fn mock_server(status: u16) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/import"))
+            .respond_with(ResponseTemplate::new(status))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn received_lines(request: &wiremock::Request) -> Vec<serde_json::Value> {
+        std::str::from_utf8(&request.body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use std::sync::atomic::AtomicUsize;
+    #[test]
+    fn test_metric() {
+        let mut writer = MetricsWriter::new("localhost:8428");
+
+        writer
+            .add(
+                "up",
+                &BTreeMap::from([("job", "node_exporter"), ("instance", "localhost:9100")]),
+                &[0, 0, 0],
+                &[
+                    Utc.timestamp_millis_opt(1549891472010).unwrap(),
+                    Utc.timestamp_millis_opt(1549891487724).unwrap(),
+                    Utc.timestamp_millis_opt(1549891503438).unwrap(),
+                ],
+            )
+            .unwrap();
+
+        writer
+            .add(
+                "up",
+                &BTreeMap::from([("job", "prometheus"), ("instance", "localhost:9090")]),
+                &[1, 1, 1],
+                &[
+                    Utc.timestamp_millis_opt(1549891461511).unwrap(),
+                    Utc.timestamp_millis_opt(1549891476511).unwrap(),
+                    Utc.timestamp_millis_opt(1549891491511).unwrap(),
+                ],
+            )
+            .unwrap();
+
+        let payload = writer.payload_string().unwrap().unwrap();
+        assert_eq!(
+            payload,
+            concat!(
+                r#"{"metric":{"__name__":"up","instance":"localhost:9100","job":"node_exporter"},"values":[0,0,0],"timestamps":[1549891472010,1549891487724,1549891503438]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"up","instance":"localhost:9090","job":"prometheus"},"values":[1,1,1],"timestamps":[1549891461511,1549891476511,1549891491511]}"#,
+                "\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_add_with_precision() {
+        let mut writer = MetricsWriter::new("localhost:8428")
+            .with_timestamp_precision(TimestampPrecision::Seconds);
+
+        let timestamps = [
+            Utc.timestamp_millis_opt(1549891472010).unwrap(),
+            Utc.timestamp_millis_opt(1549891487724).unwrap(),
+        ];
+        writer
+            .add("up", &BTreeMap::from([("job", "a")]), &[1, 1], &timestamps)
+            .unwrap();
+        writer
+            .add_with_precision(
+                "up",
+                &BTreeMap::from([("job", "b")]),
+                &[1, 1],
+                &timestamps,
+                TimestampPrecision::Milliseconds,
+            )
+            .unwrap();
+
+        let payload = writer.payload_string().unwrap().unwrap();
+        assert_eq!(
+            payload,
+            concat!(
+                r#"{"metric":{"__name__":"up","job":"a"},"values":[1,1],"timestamps":[1549891472000,1549891487000]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"up","job":"b"},"values":[1,1],"timestamps":[1549891472010,1549891487724]}"#,
+                "\r\n"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_with_deadline() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/import"))
+            .respond_with(ResponseTemplate::new(204).set_delay(Duration::from_secs(5)))
+            .mount(&server)
+            .await;
+
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        writer
+            .add(
+                "up",
+                &BTreeMap::from([("job", "a")]),
+                &[1],
+                &[Utc.timestamp_millis_opt(1549891472010).unwrap()],
+            )
+            .unwrap();
+
+        let started = Instant::now();
+        let result = writer
+            .send_with_deadline(started + Duration::from_millis(100))
+            .await;
+        assert!(matches!(result, Err(SendError::DeadlineExceeded)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_send_with_deadline_stops_retries() {
+        let server = mock_server(503).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string()).with_retries(
+            5,
+            Duration::from_millis(200),
+            Duration::from_secs(1),
+        );
+        writer
+            .add_millis("up", &[("job", "a")], &[1], &[1000])
+            .unwrap();
+        writer
+            .add_for_tenant("1", "up", &[("job", "b")], &[1], &[Utc::now()])
+            .unwrap();
+
+        let started = Instant::now();
+        let result = writer
+            .send_with_deadline(started + Duration::from_millis(300))
+            .await;
+        assert!(matches!(result, Err(SendError::DeadlineExceeded)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(server.received_requests().await.unwrap().len() < 6);
+
+        // The tenant's request never started, but its buffer is gone too.
+        assert_eq!(writer.buffer_stats(), BufferStats::default());
+        assert_eq!(writer.distinct_series(), 0);
+    }
+
+    #[test]
+    fn test_add_json_value() {
+        let mut writer = MetricsWriter::new("localhost:8428");
+
+        writer
+            .add_json_value(&serde_json::json!({
+                "metric": {"__name__": "up", "job": "a"},
+                "values": [1, 0],
+                "timestamps": [1549891472010i64, 1549891487724i64],
+            }))
+            .unwrap();
+
+        let result = writer.add_json_value(&serde_json::json!({
+            "metric": {"job": "a"},
+            "values": [1],
+            "timestamps": [1549891472010i64],
+        }));
+        assert!(matches!(result, Err(AddError::InvalidShape(_))));
+
+        let payload = writer.payload_string().unwrap().unwrap();
+        assert_eq!(
+            payload,
+            concat!(
+                r#"{"metric":{"__name__":"up","job":"a"},"timestamps":[1549891472010,1549891487724],"values":[1,0]}"#,
+                "\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_labels_from_slice() {
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+
+        let mut from_map = MetricsWriter::new("localhost:8428");
+        from_map
+            .add(
+                "up",
+                &BTreeMap::from([("job", "node_exporter"), ("instance", "localhost:9100")]),
+                &[1],
+                &timestamps,
+            )
+            .unwrap();
+
+        let mut from_slice = MetricsWriter::new("localhost:8428");
+        let labels = vec![("instance", "localhost:9100"), ("job", "node_exporter")];
+        from_slice
+            .add("up", labels.as_slice(), &[1], &timestamps)
+            .unwrap();
+
+        assert_eq!(from_map.payload().unwrap(), from_slice.payload().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_self_metrics() {
+        let server = mock_server(204).await;
+        let mut writer =
+            MetricsWriter::new(&server.address().to_string()).with_self_metrics("vm_writer");
+
+        writer
+            .add(
+                "up",
+                &BTreeMap::from([("job", "a")]),
+                &[1],
+                &[Utc.timestamp_millis_opt(1549891472010).unwrap()],
+            )
+            .unwrap();
+        writer.send().await.unwrap();
+        writer
+            .add_millis("up", &[("job", "a")], &[1], &[1000])
+            .unwrap();
+        writer.send().await.unwrap();
+        // Nothing else buffered: no request just for the self-metrics.
+        writer.send().await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        let lines = received_lines(&requests[1]);
+        let value_of = |name: &str| {
+            lines
+                .iter()
+                .find(|line| line["metric"]["__name__"] == name)
+                .map(|line| line["values"][0].clone())
+        };
+        assert_eq!(value_of("vm_writer_requests_total"), Some(1.into()));
+        assert_eq!(
+            value_of("vm_writer_bytes_total"),
+            Some(requests[0].body.len().into())
+        );
+        assert_eq!(value_of("vm_writer_failures_total"), Some(0.into()));
+        assert_eq!(writer.stats().requests, 2);
+    }
+
+    #[tokio::test]
+    async fn test_gzip_error_response_is_decoded() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"cannot parse json line").unwrap();
+        let body = encoder.finish().unwrap();
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/import"))
+            .and(header("accept-encoding", "gzip"))
+            .respond_with(
+                ResponseTemplate::new(400)
+                    .insert_header("content-encoding", "gzip")
+                    .set_body_bytes(body),
+            )
+            .mount(&server)
+            .await;
+
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        writer
+            .add(
+                "up",
+                &BTreeMap::from([("job", "a")]),
+                &[1],
+                &[Utc.timestamp_millis_opt(1549891472010).unwrap()],
+            )
+            .unwrap();
+
+        match writer.send().await {
+            Err(SendError::InvalidResponseStatusCode(status, message)) => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(message, "cannot parse json line");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_error_response_keeps_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(400)
+                    .insert_header("content-encoding", "gzip")
+                    .set_body_bytes(b"not gzip".to_vec()),
+            )
+            .mount(&server)
+            .await;
+
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        writer
+            .add_millis("up", &[("job", "a")], &[1], &[1000])
+            .unwrap();
+        match writer.send().await {
+            Err(SendError::InvalidResponseStatusCode(status, message)) => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert!(
+                    message.starts_with("<unreadable response body"),
+                    "{}",
+                    message
+                );
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_required_labels() {
+        let mut writer =
+            MetricsWriter::new("localhost:8428").with_required_labels(&["instance", "job"]);
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+
+        writer
+            .add(
+                "up",
+                &[("instance", "localhost:9100"), ("job", "node_exporter")],
+                &[1],
+                &timestamps,
+            )
+            .unwrap();
+
+        let result = writer.add("up", &[("job", "node_exporter")], &[1], &timestamps);
+        assert!(matches!(result, Err(AddError::MissingRequiredLabel(key)) if key == "instance"));
+
+        let result = writer.add_json_value(&serde_json::json!({
+            "metric": {"__name__": "up", "instance": "localhost:9100"},
+            "values": [1],
+            "timestamps": [1549891472010i64],
+        }));
+        assert!(matches!(result, Err(AddError::MissingRequiredLabel(key)) if key == "job"));
+
+        assert_eq!(writer.payload_string().unwrap().unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn test_pre_epoch_timestamps() {
+        let timestamps = [
+            Utc.timestamp_millis_opt(-1500).unwrap(),
+            Utc.timestamp_millis_opt(1549891472010).unwrap(),
+        ];
+
+        let mut writer = MetricsWriter::new("localhost:8428");
+        writer
+            .add("up", &[("job", "a")], &[1, 1], &timestamps)
+            .unwrap();
+        writer
+            .add_with_precision(
+                "up",
+                &[("job", "b")],
+                &[1, 1],
+                &timestamps,
+                TimestampPrecision::Seconds,
+            )
+            .unwrap();
+        assert_eq!(
+            writer.payload_string().unwrap().unwrap(),
+            concat!(
+                r#"{"metric":{"__name__":"up","job":"a"},"values":[1,1],"timestamps":[-1500,1549891472010]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"up","job":"b"},"values":[1,1],"timestamps":[-2000,1549891472000]}"#,
+                "\r\n"
+            )
+        );
+
+        let mut writer = MetricsWriter::new("localhost:8428").with_clamp_pre_epoch(true);
+        writer
+            .add("up", &[("job", "a")], &[1, 1], &timestamps)
+            .unwrap();
+        assert_eq!(
+            writer.payload_string().unwrap().unwrap(),
+            concat!(
+                r#"{"metric":{"__name__":"up","job":"a"},"values":[1,1],"timestamps":[0,1549891472010]}"#,
+                "\r\n"
+            )
+        );
+    }
+
+    #[cfg(feature = "rustls-tls")]
+    #[test]
+    fn test_rustls_client_builds() {
+        ClientOptions::default().build();
+        ClientOptions {
+            gzip: false,
+            ..ClientOptions::default()
+        }
+        .build();
+    }
+
+    #[test]
+    fn test_buffered_series() {
+        let mut writer = MetricsWriter::new("localhost:8428");
+        assert_eq!(writer.buffered_series().count(), 0);
+
+        let timestamps = [
+            Utc.timestamp_millis_opt(1549891472010).unwrap(),
+            Utc.timestamp_millis_opt(1549891487724).unwrap(),
+        ];
+        writer
+            .add("up", &[("job", "a")], &[1, 1], &timestamps)
+            .unwrap();
+        writer
+            .add("errors_total", &[("job", "b")], &[3], &timestamps[..1])
+            .unwrap();
+
+        let series: Vec<SeriesInfo> = writer.buffered_series().collect();
+        assert_eq!(
+            series,
+            vec![
+                SeriesInfo {
+                    name: "up".to_owned(),
+                    labels: BTreeMap::from([("job".to_owned(), "a".to_owned())]),
+                    samples: 2,
+                },
+                SeriesInfo {
+                    name: "errors_total".to_owned(),
+                    labels: BTreeMap::from([("job".to_owned(), "b".to_owned())]),
+                    samples: 1,
+                },
+            ]
+        );
+        assert_eq!(writer.payload_string().unwrap().unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn test_transform() {
+        let mut writer = MetricsWriter::new("localhost:8428").with_transform(|metric| {
+            if metric.name.starts_with("debug_") {
+                return false;
+            }
+            if let Some(env) = metric.labels.remove("env") {
+                metric.labels.insert("environment".to_owned(), env);
+            }
+            for value in &mut metric.values {
+                if let Some(v) = value.as_f64() {
+                    *value = v.round().into();
+                }
+            }
+            true
+        });
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+
+        writer
+            .add("debug_requests", &[("env", "prod")], &[1], &timestamps)
+            .unwrap();
+        writer
+            .add("temperature", &[("env", "prod")], &[21.7], &timestamps)
+            .unwrap();
+
+        assert_eq!(
+            writer.payload_string().unwrap().unwrap(),
+            concat!(
+                r#"{"metric":{"__name__":"temperature","environment":"prod"},"values":[22.0],"timestamps":[1549891472010]}"#,
+                "\r\n"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_per_tenant() {
+        let server = MockServer::start().await;
+        for tenant in ["1", "2:5"] {
+            Mock::given(method("POST"))
+                .and(path(format!("/insert/{}/prometheus/api/v1/import", tenant)))
+                .respond_with(ResponseTemplate::new(204))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+        writer
+            .add_for_tenant("1", "up", &[("job", "a")], &[1], &timestamps)
+            .unwrap();
+        writer
+            .add_for_tenant("2:5", "up", &[("job", "b")], &[0], &timestamps)
+            .unwrap();
+        writer
+            .add_for_tenant("1", "up", &[("job", "c")], &[1], &timestamps)
+            .unwrap();
+        assert_eq!(writer.buffered_series().count(), 3);
+
+        writer.send().await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].url.path(), "/insert/1/prometheus/api/v1/import");
+        assert_eq!(received_lines(&requests[0]).len(), 2);
+        assert_eq!(
+            requests[1].url.path(),
+            "/insert/2:5/prometheus/api/v1/import"
+        );
+        assert_eq!(received_lines(&requests[1]).len(), 1);
+    }
+
+    #[test]
+    fn test_add_histogram() {
+        let mut writer = MetricsWriter::new("localhost:8428");
+        writer
+            .add_histogram(
+                "request_duration_seconds",
+                &[("job", "api")],
+                &[(0.1, 2), (0.5, 5), (1.0, 7)],
+                4.2,
+                9,
+                Utc.timestamp_millis_opt(1549891472010).unwrap(),
+            )
+            .unwrap();
+
+        let series: Vec<(String, Option<String>, String)> = writer
+            .payload_string()
+            .unwrap()
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let line: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert_eq!(line["metric"]["job"], "api");
+                (
+                    line["metric"]["__name__"].as_str().unwrap().to_owned(),
+                    line["metric"]["le"].as_str().map(str::to_owned),
+                    line["values"][0].to_string(),
+                )
+            })
+            .collect();
+        let bucket = |le: &str, value: &str| {
+            (
+                "request_duration_seconds_bucket".to_owned(),
+                Some(le.to_owned()),
+                value.to_owned(),
+            )
+        };
+        assert_eq!(
+            series,
+            vec![
+                bucket("0.1", "2"),
+                bucket("0.5", "5"),
+                bucket("1", "7"),
+                bucket("+Inf", "9"),
+                (
+                    "request_duration_seconds_sum".to_owned(),
+                    None,
+                    "4.2".to_owned()
+                ),
+                (
+                    "request_duration_seconds_count".to_owned(),
+                    None,
+                    "9".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_histogram_leaves_nothing() {
+        let now = Utc.timestamp_millis_opt(1549891472010).unwrap();
+        let mut writer = MetricsWriter::localhost();
+        writer
+            .add_millis("up", &[("job", "a")], &[1], &[1])
+            .unwrap();
+        let before = writer.payload_string().unwrap();
+        assert!(matches!(
+            writer.add_histogram("h", &[("job", "a")], &[(0.1, 1)], f64::NAN, 1, now),
+            Err(AddError::NonFiniteValue(name)) if name == "h_sum"
+        ));
+        assert!(matches!(
+            writer.add_summary("s", &[("job", "a")], &[(0.5, 1.0)], f64::INFINITY, 1, now),
+            Err(AddError::NonFiniteValue(name)) if name == "s_sum"
+        ));
+        assert_eq!(writer.payload_string().unwrap(), before);
+
+        // The series a failed histogram counted towards the caps are freed.
+        let mut writer = MetricsWriter::localhost()
+            .with_max_series(3)
+            .with_max_series_per_flush(2);
+        assert!(matches!(
+            writer.add_histogram("h", &[("job", "a")], &[(0.1, 1)], 1.0, 1, now),
+            Err(AddError::CardinalityLimitExceeded(3))
+        ));
+        assert_eq!(writer.payload(), None);
+        writer
+            .add_histogram(
+                "h",
+                &[] as &[(&str, &str)],
+                &[(f64::INFINITY, 1)],
+                1.0,
+                1,
+                now,
+            )
+            .unwrap();
+        assert_eq!(writer.buffer_stats().lines, 3);
+    }
+
+    #[test]
+    fn test_add_summary() {
+        let mut writer = MetricsWriter::new("localhost:8428");
+        writer
+            .add_summary(
+                "rpc_duration_seconds",
+                &[("job", "api")],
+                &[(0.5, 0.05), (0.9, 0.12), (0.99, 0.27)],
+                17.5,
+                200,
+                Utc.timestamp_millis_opt(1549891472010).unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            writer.payload_string().unwrap().unwrap(),
+            concat!(
+                r#"{"metric":{"__name__":"rpc_duration_seconds","job":"api","quantile":"0.5"},"values":[0.05],"timestamps":[1549891472010]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"rpc_duration_seconds","job":"api","quantile":"0.9"},"values":[0.12],"timestamps":[1549891472010]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"rpc_duration_seconds","job":"api","quantile":"0.99"},"values":[0.27],"timestamps":[1549891472010]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"rpc_duration_seconds_sum","job":"api"},"values":[17.5],"timestamps":[1549891472010]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"rpc_duration_seconds_count","job":"api"},"values":[200],"timestamps":[1549891472010]}"#,
+                "\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_payload_accessors() {
+        let mut writer = MetricsWriter::new("localhost:8428");
+        assert_eq!(writer.payload(), None);
+        assert_eq!(writer.payload_string().unwrap(), None);
+
+        writer
+            .add(
+                "up",
+                &[("job", "a")],
+                &[1],
+                &[Utc.timestamp_millis_opt(1549891472010).unwrap()],
+            )
+            .unwrap();
+
+        let expected = concat!(
+            r#"{"metric":{"__name__":"up","job":"a"},"values":[1],"timestamps":[1549891472010]}"#,
+            "\r\n"
+        );
+        assert_eq!(writer.payload(), Some(expected.as_bytes()));
+        assert_eq!(writer.payload_string(), Ok(Some(expected.to_owned())));
+
+        writer.buffer.append(&[0xff]);
+        assert!(writer.payload().is_some());
+        assert!(writer.payload_string().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redact_label() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/import"))
+            .respond_with(
+                ResponseTemplate::new(400)
+                    .set_body_string(r#"cannot parse line {"user_token":"s3cr3t"}"#),
+            )
+            .mount(&server)
+            .await;
+
+        let mut writer =
+            MetricsWriter::new(&server.address().to_string()).redact_label("user_token");
+        writer
+            .add(
+                "logins_total",
+                &[("user_token", "s3cr3t")],
+                &[1],
+                &[Utc.timestamp_millis_opt(1549891472010).unwrap()],
+            )
+            .unwrap();
+
+        let error = writer.send().await.unwrap_err();
+        match &error {
+            SendError::InvalidResponseStatusCode(_, message) => {
+                assert_eq!(message, r#"cannot parse line {"user_token":"***"}"#)
+            }
+            error => panic!("unexpected error {:?}", error),
+        }
+        assert!(!error.to_string().contains("s3cr3t"));
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(
+            received_lines(&requests[0])[0]["metric"]["user_token"],
+            "s3cr3t"
+        );
+    }
+
+    #[test]
+    fn test_strict_value_types() {
+        let mut writer = MetricsWriter::new("localhost:8428").with_strict_value_types(true);
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+
+        writer
+            .add("up", &[("job", "a")], &[1], &timestamps)
+            .unwrap();
+        writer
+            .add("up", &[("job", "b")], &[0], &timestamps)
+            .unwrap();
+        writer
+            .add("temperature", &[("job", "a")], &[21.5], &timestamps)
+            .unwrap();
+
+        let result = writer.add("up", &[("job", "c")], &[0.5], &timestamps);
+        assert!(matches!(result, Err(AddError::ValueTypeConflict(name)) if name == "up"));
+        let result = writer.add("load", &[("job", "a")], &[1.0], &timestamps);
+        assert!(result.is_ok());
+        assert_eq!(writer.buffered_series().count(), 4);
+
+        let mut lenient = MetricsWriter::new("localhost:8428");
+        lenient
+            .add("up", &[("job", "a")], &[1], &timestamps)
+            .unwrap();
+        lenient
+            .add("up", &[("job", "b")], &[0.5], &timestamps)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_query_params() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/import"))
+            .and(query_param("debug", "1"))
+            .and(query_param("trace", "true"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut writer = MetricsWriter::new(&server.address().to_string())
+            .with_query_param("debug", "1")
+            .with_query_param("trace", "true");
+        writer
+            .add_millis("up", &[("job", "a")], &[1], &[1549891472010])
+            .unwrap();
+        writer.send().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_gzip_detection() {
+        // A probe rejected with an error status is retried on the next send.
+        for (status, accept_encoding, expect_gzip, probes) in [
+            (204, Some("deflate, gzip"), true, 1),
+            (204, None, false, 1),
+            (405, None, false, 2),
+        ] {
+            let server = mock_server(204).await;
+            let mut options = ResponseTemplate::new(status);
+            if let Some(accept_encoding) = accept_encoding {
+                options = options.insert_header("accept-encoding", accept_encoding);
+            }
+            Mock::given(method("OPTIONS"))
+                .and(path("/api/v1/import"))
+                .respond_with(options)
+                .expect(probes)
+                .mount(&server)
+                .await;
+
+            let mut writer = MetricsWriter::new(&server.address().to_string())
+                .with_gzip(GzipLevel::Default)
+                .with_gzip_detection(true);
+            for _ in 0..2 {
+                writer
+                    .add_millis("up", &[("job", "a")], &[1], &[1549891472010])
+                    .unwrap();
+                writer.send().await.unwrap();
+            }
+
+            let encoding = wiremock::http::HeaderName::from("content-encoding");
+            let posts: Vec<_> = server
+                .received_requests()
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|request| request.method == wiremock::http::Method::Post)
+                .collect();
+            assert_eq!(posts.len(), 2);
+            for post in posts {
+                assert_eq!(post.headers.contains_key(&encoding), expect_gzip);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gzip_min_size() {
+        let server = mock_server(204).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string())
+            .with_gzip(GzipLevel::Default)
+            .with_gzip_min_size(1024);
+
+        writer
+            .add_millis("up", &[("job", "a")], &[1], &[1549891472010])
+            .unwrap();
+        writer.send().await.unwrap();
+        let timestamps: Vec<i64> = (0..100).map(|i| 1549891472010 + i * 1000).collect();
+        writer
+            .add_millis("up", &[("job", "a")], &vec![1; 100], &timestamps)
+            .unwrap();
+        writer.send().await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let encoding = wiremock::http::HeaderName::from("content-encoding");
+        assert!(!requests[0].headers.contains_key(&encoding));
+        assert_eq!(received_lines(&requests[0]).len(), 1);
+        assert_eq!(requests[1].headers[&encoding].as_str(), "gzip");
+    }
+
+    #[tokio::test]
+    async fn test_send_gzip() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/import"))
+            .and(header("content-encoding", "gzip"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut writer =
+            MetricsWriter::new(&server.address().to_string()).with_gzip(GzipLevel::Best);
+        writer
+            .add(
+                "up",
+                &[("job", "a")],
+                &[1],
+                &[Utc.timestamp_millis_opt(1549891472010).unwrap()],
+            )
+            .unwrap();
+        let payload = writer.payload().unwrap().to_vec();
+        writer.send().await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&requests[0].body[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, payload);
+    }
+
+    #[tokio::test]
+    async fn test_replay_file() {
+        let server = mock_server(204).await;
+        let lines = concat!(
+            r#"{"metric":{"__name__":"up","job":"a"},"values":[1],"timestamps":[1549891472010]}"#,
+            "\r\n",
+            r#"{"metric":{"__name__":"up","job":"b"},"values":[0],"timestamps":[1549891472010]}"#,
+        );
+        let path = std::env::temp_dir().join(format!(
+            "victoria-metrics-writer-replay-{}.jsonl",
+            std::process::id()
+        ));
+        std::fs::write(&path, lines).unwrap();
+
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        let result = writer.replay_file(&path).await;
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].body, format!("{}\r\n", lines).into_bytes());
+        assert_eq!(writer.payload(), None);
+    }
+
+    #[test]
+    fn test_display_labels() {
+        let mut writer = MetricsWriter::new("localhost:8428");
+        writer
+            .add(
+                "up",
+                &DisplayLabels(&[("job", &"api"), ("ratio", &0.5), ("shard", &3)]),
+                &[1],
+                &[Utc.timestamp_millis_opt(1549891472010).unwrap()],
+            )
+            .unwrap();
+
+        assert_eq!(
+            writer.payload_string().unwrap().unwrap(),
+            concat!(
+                r#"{"metric":{"__name__":"up","job":"api","ratio":"0.5","shard":"3"},"values":[1],"timestamps":[1549891472010]}"#,
+                "\r\n"
+            )
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker() {
+        let server = mock_server(503).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string())
+            .with_circuit_breaker(2, Duration::from_millis(200));
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+
+        for _ in 0..2 {
+            writer
+                .add("up", &[("job", "a")], &[1], &timestamps)
+                .unwrap();
+            let result = writer.send().await;
+            assert!(matches!(
+                result,
+                Err(SendError::InvalidResponseStatusCode(..))
+            ));
+        }
+
+        writer
+            .add("up", &[("job", "a")], &[1], &timestamps)
+            .unwrap();
+        let result = writer.send().await;
+        assert!(matches!(result, Err(SendError::CircuitOpen)));
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+        assert!(writer.payload().is_some());
+
+        // Still open just before the cooldown ends.
+        tokio::time::advance(Duration::from_millis(199)).await;
+        assert!(matches!(writer.send().await, Err(SendError::CircuitOpen)));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        let result = writer.send().await;
+        assert!(matches!(
+            result,
+            Err(SendError::InvalidResponseStatusCode(..))
+        ));
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+
+        writer
+            .add("up", &[("job", "a")], &[1], &timestamps)
+            .unwrap();
+        let result = writer.send().await;
+        assert!(matches!(result, Err(SendError::CircuitOpen)));
+    }
+
+    #[test]
+    fn test_add_millis() {
+        let mut writer = MetricsWriter::new("localhost:8428")
+            .with_timestamp_precision(TimestampPrecision::Seconds)
+            .with_clamp_pre_epoch(true);
+        writer
+            .add_millis(
+                "up",
+                &[("job", "a")],
+                &[1, 0, 1],
+                &[-1, 1549891472010, 1549891487724],
+            )
+            .unwrap();
+
+        assert_eq!(
+            writer.payload_string().unwrap().unwrap(),
+            concat!(
+                r#"{"metric":{"__name__":"up","job":"a"},"values":[1,0,1],"timestamps":[-1,1549891472010,1549891487724]}"#,
+                "\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_localhost() {
+        for writer in [MetricsWriter::localhost(), MetricsWriter::default()] {
+            assert_eq!(
+                build_import_url(&writer.url_config),
+                "http://localhost:8428/api/v1/import"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accepted_statuses() {
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+        for (status, accepted) in [(202, true), (302, true), (400, false)] {
+            // A redirect without `Location` is not followed, so each server
+            // answers exactly one request.
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(status))
+                .expect(1)
+                .mount(&server)
+                .await;
+            let mut writer = MetricsWriter::new(&server.address().to_string())
+                .with_accepted_status(StatusCode::FOUND);
+            writer
+                .add("up", &[("job", "a")], &[1], &timestamps)
+                .unwrap();
+
+            match writer.send().await {
+                Ok(()) => assert!(accepted, "status {}", status),
+                Err(SendError::InvalidResponseStatusCode(code, _)) => {
+                    assert!(!accepted, "status {}", status);
+                    assert_eq!(code.as_u16(), status);
+                }
+                Err(err) => panic!("unexpected error {:?}", err),
+            }
+            server.verify().await;
+        }
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        let mut writer = MetricsWriter::new("localhost:8428").redact_label("token");
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+        writer
+            .add("up", &[("job", "a")], &[1], &timestamps)
+            .unwrap();
+        writer
+            .add("logins_total", &[("token", "s3cr3t")], &[2], &timestamps)
+            .unwrap();
+
+        assert_eq!(
+            writer.debug_dump(),
+            r#"[
+  {
+    "metric": {
+      "__name__": "up",
+      "job": "a"
+    },
+    "timestamps": [
+      1549891472010
+    ],
+    "values": [
+      1
+    ]
+  },
+  {
+    "metric": {
+      "__name__": "logins_total",
+      "token": "***"
+    },
+    "timestamps": [
+      1549891472010
+    ],
+    "values": [
+      2
+    ]
+  }
+]"#
+        );
+        assert_eq!(writer.buffered_series().count(), 2);
+        assert!(writer.payload_string().unwrap().unwrap().contains("s3cr3t"));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_options() {
+        let server = mock_server(204).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string())
+            .with_tcp_keepalive(Duration::from_secs(30))
+            .with_tcp_nodelay(false);
+        assert_eq!(
+            writer.client_options.tcp_keepalive,
+            Some(Duration::from_secs(30))
+        );
+        assert!(!writer.client_options.tcp_nodelay);
+
+        writer
+            .add(
+                "up",
+                &[("job", "a")],
+                &[1],
+                &[Utc.timestamp_millis_opt(1549891472010).unwrap()],
+            )
+            .unwrap();
+        writer.send().await.unwrap();
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_distinct_series() {
+        let mut writer = MetricsWriter::new("localhost:8428").with_max_series(3);
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+
+        writer
+            .add("up", &[("job", "a")], &[1], &timestamps)
+            .unwrap();
+        writer
+            .add("up", &[("job", "b")], &[1], &timestamps)
+            .unwrap();
+        writer
+            .add("up", &[("job", "a")], &[0], &timestamps)
+            .unwrap();
+        writer
+            .add_for_tenant("1", "up", &[("job", "a")], &[1], &timestamps)
+            .unwrap();
+        assert_eq!(writer.buffered_series().count(), 4);
+        assert_eq!(writer.distinct_series(), 3);
+
+        let result = writer.add("up", &[("job", "c")], &[1], &timestamps);
+        assert!(matches!(result, Err(AddError::CardinalityLimitExceeded(3))));
+        writer
+            .add("up", &[("job", "b")], &[0], &timestamps)
+            .unwrap();
+        assert_eq!(writer.distinct_series(), 3);
+
+        let mut unlimited = MetricsWriter::new("localhost:8428");
+        for job in ["a", "b", "c", "d", "a"] {
+            unlimited
+                .add("up", &[("job", job)], &[1], &timestamps)
+                .unwrap();
+        }
+        assert_eq!(unlimited.distinct_series(), 4);
+    }
+
+    #[test]
+    fn test_monotonic_timestamps() {
+        let mut writer = MetricsWriter::new("localhost:8428").with_monotonic_timestamps(true);
+        let labels = [("job", "a")];
+
+        writer
+            .add_millis("up", &labels, &[1, 1, 1], &[1000, 2000, 3000])
+            .unwrap();
+        let result = writer.add_millis("up", &labels, &[1, 1], &[2000, 2000]);
+        assert!(matches!(result, Err(AddError::NonMonotonicTimestamps)));
+        let result = writer.add_millis("up", &labels, &[1, 1], &[3000, 2000]);
+        assert!(matches!(result, Err(AddError::NonMonotonicTimestamps)));
+        let result = writer.add_json_value(&serde_json::json!({
+            "metric": {"__name__": "up"},
+            "values": [1, 1],
+            "timestamps": [3000, 2000],
+        }));
+        assert!(matches!(result, Err(AddError::NonMonotonicTimestamps)));
+        assert_eq!(writer.buffered_series().count(), 1);
+
+        let mut lenient = MetricsWriter::new("localhost:8428");
+        lenient
+            .add_millis("up", &labels, &[1, 1], &[3000, 2000])
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_error_parser() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/import"))
+            .respond_with(
+                ResponseTemplate::new(422)
+                    .set_body_string(r#"{"status":"error","error":"unsupported value type"}"#),
+            )
+            .mount(&server)
+            .await;
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        writer
+            .add("up", &[("job", "a")], &[1], &timestamps)
+            .unwrap();
+        match writer.send().await {
+            Err(SendError::InvalidResponseStatusCode(_, message)) => assert_eq!(
+                message,
+                r#"{"status":"error","error":"unsupported value type"}"#
+            ),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let mut writer =
+            MetricsWriter::new(&server.address().to_string()).with_error_parser(|status, body| {
+                serde_json::from_slice::<serde_json::Value>(body)
+                    .ok()
+                    .and_then(|body| body["error"].as_str().map(str::to_owned))
+                    .unwrap_or_else(|| text_error_parser(status, body))
+            });
+        writer
+            .add("up", &[("job", "a")], &[1], &timestamps)
+            .unwrap();
+        match writer.send().await {
+            Err(SendError::InvalidResponseStatusCode(status, message)) => {
+                assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+                assert_eq!(message, "unsupported value type");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buffer_writer() {
+        let server = mock_server(204).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        writer
+            .add(
+                "up",
+                &[("job", "a")],
+                &[1],
+                &[Utc.timestamp_millis_opt(1549891472010).unwrap()],
+            )
+            .unwrap();
+
+        let line = concat!(
+            r#"{"metric":{"__name__":"up","job":"b"},"values":[0],"timestamps":[1549891472010]}"#,
+            "\r\n"
+        );
+        let mut buffer_writer = writer.buffer_writer();
+        for chunk in line.as_bytes().chunks(16) {
+            buffer_writer.write_all(chunk).await.unwrap();
+        }
+        buffer_writer.flush().await.unwrap();
+        assert_eq!(writer.buffered_series().count(), 2);
+
+        writer.send().await.unwrap();
+        let requests = server.received_requests().await.unwrap();
+        let lines = received_lines(&requests[0]);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["metric"]["job"], "b");
+    }
+
+    /// Starts a server answering every request with 204 that records in
+    /// `peak` the most requests it was handling at once. Wiremock cannot
+    /// hold a response open without serializing requests, so this is a bare
+    /// TCP server. Each response is held until `together` requests are in,
+    /// or all of `total` have arrived, so that a working limit is reached
+    /// and a broken one exceeded.
+    async fn concurrency_server(together: usize, total: usize) -> (String, Arc<AtomicUsize>) {
+        use std::sync::atomic::Ordering::SeqCst;
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let peak = Arc::new(AtomicUsize::new(0));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::new(AtomicUsize::new(0));
+        let arrived = Arc::new(tokio::sync::Notify::new());
+        let server_peak = peak.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let peak = server_peak.clone();
+                let in_flight = in_flight.clone();
+                let seen = seen.clone();
+                let arrived = arrived.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut chunk = [0; 4096];
+                    loop {
+                        let read = socket.read(&mut chunk).await.unwrap();
+                        if read == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&chunk[..read]);
+                        let text = String::from_utf8_lossy(&request).to_lowercase();
+                        if let Some(end) = text.find("\r\n\r\n") {
+                            let length = text
+                                .lines()
+                                .find_map(|line| line.strip_prefix("content-length:"))
+                                .map_or(0, |length| length.trim().parse().unwrap());
+                            if request.len() >= end + 4 + length {
+                                break;
+                            }
+                        }
+                    }
+
+                    peak.fetch_max(in_flight.fetch_add(1, SeqCst) + 1, SeqCst);
+                    seen.fetch_add(1, SeqCst);
+                    arrived.notify_waiters();
+                    loop {
+                        let notified = arrived.notified();
+                        if in_flight.load(SeqCst) >= together || seen.load(SeqCst) == total {
+                            break;
+                        }
+                        notified.await;
+                    }
+                    // Give requests beyond a broken limit time to arrive.
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    in_flight.fetch_sub(1, SeqCst);
+                    socket
+                        .write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n")
+                        .await
+                        .unwrap();
+                });
+            }
+        });
+        (address, peak)
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_limit() {
+        let (address, peak) = concurrency_server(2, 5).await;
+
+        let limit = InFlightLimit::new(2);
+        let mut tasks = Vec::new();
+        for i in 0..5 {
+            let mut writer = MetricsWriter::new(&address).with_in_flight_limit(limit.clone());
+            writer
+                .add_millis("up", &[("i", i.to_string())], &[1], &[1549891472010])
+                .unwrap();
+            tasks.push(tokio::spawn(async move { writer.send().await }));
+        }
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(peak.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_add_with_interval() {
+        let mut writer = MetricsWriter::new("localhost:8428");
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+        writer
+            .add_with_interval(
+                "up",
+                &[("job", "a")],
+                Duration::from_secs(15),
+                &[1],
+                &timestamps,
+            )
+            .unwrap();
+        writer
+            .add_with_interval(
+                "up",
+                &[("job", "b")],
+                Duration::from_millis(1500),
+                &[1],
+                &timestamps,
+            )
+            .unwrap();
+
+        assert_eq!(
+            writer.payload_string().unwrap().unwrap(),
+            concat!(
+                r#"{"metric":{"__name__":"up","job":"a","scrape_interval":"15s"},"values":[1],"timestamps":[1549891472010]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"up","job":"b","scrape_interval":"1500ms"},"values":[1],"timestamps":[1549891472010]}"#,
+                "\r\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_add_counter_and_gauge() {
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+        let mut expected = MetricsWriter::new("localhost:8428");
+        expected
+            .add("requests_total", &[("job", "api")], &[42], &timestamps)
+            .unwrap();
+        expected
+            .add("temperature", &[("room", "a")], &[21.5], &timestamps)
+            .unwrap();
+
+        let mut writer = MetricsWriter::new("localhost:8428");
+        writer
+            .add_counter("requests_total", &[("job", "api")], &[42], &timestamps)
+            .unwrap();
+        writer
+            .add_gauge("temperature", &[("room", "a")], &[21.5], &timestamps)
+            .unwrap();
+
+        assert_eq!(writer.payload(), expected.payload());
+    }
+
+    #[tokio::test]
+    async fn test_from_env() {
+        let server = mock_server(204).await;
+        let address = server.address().to_string();
+        let env = BTreeMap::from([
+            ("VM_HOST", address.as_str()),
+            ("VM_SCHEME", "http"),
+            ("VM_PATH_PREFIX", ""),
+            ("VM_TENANT", "1:2"),
+            ("VM_AUTH_TOKEN", "secret"),
+        ]);
+        let lookup = |key: &str| env.get(key).map(|value| value.to_string());
+
+        let mut writer = MetricsWriter::from_env_lookup(lookup).unwrap();
+        assert_eq!(
+            writer.url_config,
+            ImportUrlConfig {
+                tenant: Some("1:2".to_owned()),
+                ..ImportUrlConfig::new(&address)
+            }
+        );
+
+        Mock::given(method("POST"))
+            .and(path("/insert/1:2/prometheus/api/v1/import"))
+            .and(header("authorization", "Bearer secret"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        writer
+            .add_millis("up", &[("job", "a")], &[1], &[1549891472010])
+            .unwrap();
+        writer.send().await.unwrap();
+    }
+
+    #[test]
+    fn test_from_env_errors() {
+        assert_eq!(
+            MetricsWriter::from_env_lookup(|_| None).err(),
+            Some(ConfigError::MissingVariable("VM_HOST"))
+        );
+        let lookup = |key: &str| match key {
+            "VM_HOST" => Some("localhost:8428".to_owned()),
+            "VM_SCHEME" => Some("ftp".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            MetricsWriter::from_env_lookup(lookup).err(),
+            Some(ConfigError::InvalidVariable("VM_SCHEME", "ftp".to_owned()))
+        );
+        let lookup = |key: &str| match key {
+            "VM_HOST" => Some("vminsert:8480".to_owned()),
+            "VM_TENANT" => Some("team a".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            MetricsWriter::from_env_lookup(lookup).err(),
+            Some(ConfigError::InvalidVariable(
+                "VM_TENANT",
+                "team a".to_owned()
+            ))
+        );
+        let lookup = |key: &str| match key {
+            "VM_HOST" => Some("proxy".to_owned()),
+            "VM_PATH_PREFIX" => Some("vm".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            MetricsWriter::from_env_lookup(lookup).err(),
+            Some(ConfigError::InvalidVariable(
+                "VM_PATH_PREFIX",
+                "vm".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_payload_checksum() {
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+        let writer_with = |value: i32| {
+            let mut writer = MetricsWriter::new("localhost:8428");
+            writer
+                .add("up", &[("job", "a")], &[value], &timestamps)
+                .unwrap();
+            writer
+        };
+
+        assert_eq!(MetricsWriter::localhost().payload_checksum(), None);
+        let checksum = writer_with(1).payload_checksum();
+        assert!(checksum.is_some());
+        assert_eq!(writer_with(1).payload_checksum(), checksum);
+        assert_ne!(writer_with(0).payload_checksum(), checksum);
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let server = mock_server(204).await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/export"))
+            .and(query_param("match[]", "up"))
+            .and(query_param("start", "2019-02-11T13:24:32.010Z"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(concat!(
+                r#"{"metric":{"__name__":"up","job":"a"},"values":[1,0],"timestamps":[1549891472010,1549891487724]}"#,
+                "\n",
+                r#"{"metric":{"__name__":"up","job":"b"},"values":[1],"timestamps":[1549891472010]}"#,
+                "\n",
+            )))
+            .mount(&server)
+            .await;
+
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        let start = Utc.timestamp_millis_opt(1549891472010).unwrap();
+        let series: Vec<MetricData> = writer
+            .export("up", Some(start), None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            series,
+            vec![
+                MetricData {
+                    name: "up".to_owned(),
+                    labels: BTreeMap::from([("job".to_owned(), "a".to_owned())]),
+                    values: vec![1.into(), 0.into()],
+                    timestamps: vec![1549891472010, 1549891487724],
+                },
+                MetricData {
+                    name: "up".to_owned(),
+                    labels: BTreeMap::from([("job".to_owned(), "b".to_owned())]),
+                    values: vec![1.into()],
+                    timestamps: vec![1549891472010],
+                },
+            ]
+        );
+
+        for metric in &series {
+            writer
+                .add_millis(
+                    &metric.name,
+                    &metric.labels,
+                    &metric.values,
+                    &metric.timestamps,
+                )
+                .unwrap();
+        }
+        writer.send().await.unwrap();
+        let requests = server.received_requests().await.unwrap();
+        let imported = received_lines(&requests[1]);
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[0]["values"], serde_json::json!([1, 0]));
+    }
+
+    #[tokio::test]
+    async fn test_export_unreadable_error_keeps_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(503)
+                    .insert_header("content-encoding", "gzip")
+                    .set_body_bytes(b"not gzip".to_vec()),
+            )
+            .mount(&server)
+            .await;
+
+        let writer = MetricsWriter::new(&server.address().to_string());
+        let result: Result<Vec<MetricData>, _> =
+            writer.export("up", None, None).try_collect().await;
+        match result {
+            Err(SendError::InvalidResponseStatusCode(status, message)) => {
+                assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+                assert!(
+                    message.starts_with("<unreadable response body"),
+                    "{}",
+                    message
+                );
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_drain_up_to() {
+        let line = |job: &str| {
+            format!(
+                r#"{{"metric":{{"__name__":"up","job":"{}"}},"values":[1],"timestamps":[1549891472010]}}"#,
+                job
+            ) + "\r\n"
+        };
+        let mut writer = MetricsWriter::new("localhost:8428").with_max_series(3);
+        for job in ["a", "b", "c"] {
+            writer
+                .add_millis("up", &[("job", job)], &[1], &[1549891472010])
+                .unwrap();
+        }
+        let len = line("a").len();
+
+        assert_eq!(
+            writer.drain_up_to(2 * len + 10).unwrap(),
+            line("a") + &line("b")
+        );
+        assert_eq!(writer.payload_string().unwrap().unwrap(), line("c"));
+        // Drained series no longer count towards the cardinality cap.
+        writer
+            .add_millis("up", &[("job", "d")], &[1], &[1549891472010])
+            .unwrap();
+
+        assert_eq!(writer.drain_up_to(1).unwrap(), line("c"));
+        assert_eq!(writer.drain_up_to(usize::MAX).unwrap(), line("d"));
+        assert_eq!(writer.drain_up_to(usize::MAX), None);
+    }
+
+    #[tokio::test]
+    async fn test_drain_up_to_leaves_partial_line() {
+        let line = r#"{"metric":{"__name__":"up"},"values":[1],"timestamps":[1549891472010]}"#;
+        let mut writer = MetricsWriter::localhost();
+        writer
+            .buffer_writer()
+            .write_all(&line.as_bytes()[..10])
+            .await
+            .unwrap();
+        assert_eq!(writer.drain_up_to(usize::MAX), None);
+
+        writer
+            .buffer_writer()
+            .write_all(format!("{}\n{}", &line[10..], &line[..10]).as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            writer.drain_up_to(usize::MAX).unwrap(),
+            format!("{}\n", line)
+        );
+        assert_eq!(writer.drain_up_to(usize::MAX), None);
+
+        writer
+            .buffer_writer()
+            .write_all(format!("{}\n", &line[10..]).as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            writer.drain_up_to(usize::MAX).unwrap(),
+            format!("{}\n", line)
+        );
+        assert_eq!(writer.payload(), None);
+    }
+
+    #[test]
+    fn test_add_matches_add_millis() {
+        let timestamps = [
+            Utc.timestamp_millis_opt(-1500).unwrap(),
+            Utc.timestamp_millis_opt(1549891472010).unwrap(),
+            Utc.timestamp_millis_opt(1549891487724).unwrap(),
+        ];
+        for precision in [
+            TimestampPrecision::Seconds,
+            TimestampPrecision::Milliseconds,
+        ] {
+            for clamp in [false, true] {
+                let mut writer = MetricsWriter::localhost()
+                    .with_timestamp_precision(precision)
+                    .with_clamp_pre_epoch(clamp);
+                writer
+                    .add("up", &[("job", "a")], &[1, 2, 3], &timestamps)
+                    .unwrap();
+
+                let millis: Vec<i64> = timestamps
+                    .iter()
+                    .map(|ts| precision.millis(ts))
+                    .map(|ts| if clamp { ts.max(0) } else { ts })
+                    .collect();
+                let mut expected = MetricsWriter::localhost();
+                expected
+                    .add_millis("up", &[("job", "a")], &[1, 2, 3], &millis)
+                    .unwrap();
+                assert_eq!(writer.payload(), expected.payload());
+                let timestamps = format!(
+                    r#""timestamps":{}}}"#,
+                    serde_json::to_string(&millis).unwrap()
+                );
+                assert!(writer
+                    .payload_string()
+                    .unwrap()
+                    .unwrap()
+                    .ends_with(&format!("{}\r\n", timestamps)));
+            }
+        }
+    }
+
+    #[cfg(feature = "middleware")]
+    #[tokio::test]
+    async fn test_middleware_client() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct CountRequests(Arc<AtomicUsize>);
+
+        #[async_trait::async_trait]
+        impl reqwest_middleware::Middleware for CountRequests {
+            async fn handle(
+                &self,
+                request: reqwest::Request,
+                extensions: &mut task_local_extensions::Extensions,
+                next: reqwest_middleware::Next<'_>,
+            ) -> reqwest_middleware::Result<reqwest::Response> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                next.run(request, extensions).await
+            }
+        }
+
+        let server = mock_server(204).await;
+        let count = Arc::new(AtomicUsize::new(0));
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
+            .with(CountRequests(count.clone()))
+            .build();
+        let mut writer =
+            MetricsWriter::new(&server.address().to_string()).with_middleware_client(client);
+
+        for _ in 0..2 {
+            writer
+                .add_millis("up", &[("job", "a")], &[1], &[1549891472010])
+                .unwrap();
+            writer.send().await.unwrap();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let labels = [("job", "a"), ("instance", "x"), ("job", "b")];
+        let add = |policy| {
+            let mut writer = MetricsWriter::localhost().with_duplicate_labels(policy);
+            writer
+                .add_millis("up", &labels, &[1], &[1549891472010])
+                .map(|()| writer.payload_string().unwrap().unwrap())
+        };
+
+        assert!(matches!(
+            add(DuplicateLabelPolicy::Error),
+            Err(AddError::DuplicateLabel(key)) if key == "job"
+        ));
+        assert_eq!(
+            add(DuplicateLabelPolicy::KeepFirst).unwrap(),
+            r#"{"metric":{"__name__":"up","job":"a","instance":"x"},"values":[1],"timestamps":[1549891472010]}"#.to_owned() + "\r\n"
+        );
+        assert_eq!(
+            add(DuplicateLabelPolicy::KeepLast).unwrap(),
+            r#"{"metric":{"__name__":"up","instance":"x","job":"b"},"values":[1],"timestamps":[1549891472010]}"#.to_owned() + "\r\n"
+        );
+
+        // Past the 16 keys checked without allocating.
+        let keys: Vec<String> = (0..20).map(|i| format!("k{}", i)).collect();
+        let mut labels: Vec<(&str, &str)> = keys.iter().map(|key| (key.as_str(), "v")).collect();
+        labels.push(("k18", "w"));
+        let mut writer = MetricsWriter::localhost();
+        assert!(matches!(
+            writer.add_millis("up", &labels, &[1], &[1549891472010]),
+            Err(AddError::DuplicateLabel(key)) if key == "k18"
+        ));
+    }
+
+    /// Counts how often its labels are visited.
+    struct CountingLabels<'a> {
+        labels: &'a [(&'a str, &'a str)],
+        unique: bool,
+        visits: std::cell::Cell<usize>,
+    }
+
+    impl Labels for CountingLabels<'_> {
+        fn for_each_label(&self, f: &mut dyn FnMut(&str, &str)) {
+            self.visits.set(self.visits.get() + 1);
+            self.labels.for_each_label(f)
+        }
+
+        fn is_unique(&self) -> bool {
+            self.unique
+        }
+    }
+
+    #[test]
+    fn test_duplicate_check_visits_labels_once() {
+        for (unique, visits) in [(false, 2), (true, 1)] {
+            let labels = CountingLabels {
+                labels: &[("job", "a"), ("instance", "x"), ("zone", "b")],
+                unique,
+                visits: Default::default(),
+            };
+            let mut writer = MetricsWriter::localhost();
+            writer
+                .add_millis("up", &labels, &[1], &[1549891472010])
+                .unwrap();
+            // Serializing the line is the one visit that remains.
+            assert_eq!(labels.visits.get(), visits, "unique {}", unique);
+        }
+        assert!(BTreeMap::from([("job", "a")]).is_unique());
+        assert!(!["job", "a"].map(|key| (key, key)).is_unique());
+    }
+
+    #[tokio::test]
+    async fn test_add_with_flush() {
+        let server = mock_server(204).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+
+        let mut flushed = Vec::new();
+        for job in ["a", "b", "c", "d", "e"] {
+            let flush = writer
+                .add_with_flush("up", &[("job", job)], &[1], &timestamps, |stats| {
+                    stats.lines == 3
+                })
+                .await
+                .unwrap();
+            flushed.push(flush);
+        }
+        assert_eq!(flushed, [false, false, true, false, false]);
+        assert_eq!(writer.buffer_stats().lines, 2);
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(received_lines(&requests[0]).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_tenant_url() {
+        let server = mock_server(204).await;
+        let address = server.address().to_string();
+        let mut writer = MetricsWriter::new(&address);
+        assert!(matches!(
+            writer.add_for_tenant(
+                "team a",
+                "up",
+                &[("job", "a")],
+                &[1],
+                &[Utc.timestamp_millis_opt(1549891472010).unwrap()],
+            ),
+            Err(AddError::InvalidTenant(tenant)) if tenant == "team a"
+        ));
+        assert_eq!(writer.buffer_stats(), BufferStats::default());
+
+        assert!(matches!(
+            MetricsWriter::new(&address).with_tenant("1:"),
+            Err(ConfigError::InvalidUrl(message)) if message.contains("\"1:\"")
+        ));
+        assert!(matches!(
+            MetricsWriter::localhost().with_url_config(ImportUrlConfig::new("local host")),
+            Err(ConfigError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            MetricsWriter::localhost().with_url_config(ImportUrlConfig {
+                path_prefix: Some("/vm?debug=1".to_owned()),
+                ..ImportUrlConfig::new("proxy")
+            }),
+            Err(ConfigError::InvalidUrl(message)) if message.starts_with("path prefix")
+        ));
+
+        // A config passed in unchecked still fails when sending.
+        let mut writer = MetricsWriter::from_url_config(ImportUrlConfig {
+            tenant: Some("team a".to_owned()),
+            ..ImportUrlConfig::new(&address)
+        });
+        writer
+            .add_millis("up", &[("job", "a")], &[1], &[1549891472010])
+            .unwrap();
+        let err = writer.send().await.unwrap_err();
+        assert!(
+            matches!(&err, SendError::InvalidUrl(message) if message.contains("\"team a\"")),
+            "{}",
+            err
+        );
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_last_write_wins() {
+        let server = mock_server(204).await;
+        let mut writer =
+            MetricsWriter::new(&server.address().to_string()).with_last_write_wins(true);
+        writer
+            .add_millis("temp", &[("room", "a")], &[20, 21], &[1000, 2000])
+            .unwrap();
+        writer
+            .add_millis("temp", &[("room", "b")], &[30], &[1000])
+            .unwrap();
+        writer
+            .add_millis("temp", &[("room", "a")], &[22, 23], &[2000, 3000])
+            .unwrap();
+        writer.send().await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&requests[0].body).unwrap(),
+            concat!(
+                r#"{"metric":{"__name__":"temp","room":"a"},"values":[20,22,23],"timestamps":[1000,2000,3000]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"temp","room":"b"},"values":[30],"timestamps":[1000]}"#,
+                "\r\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_last_send_ok() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        assert_eq!(writer.last_send_ok(), None);
+        for expected in [false, true] {
+            writer
+                .add_millis("up", &[("job", "a")], &[1], &[1549891472010])
+                .unwrap();
+            assert_eq!(writer.send().await.is_ok(), expected);
+            assert_eq!(writer.last_send_ok(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn test_value_serialization() {
+        fn written<T: Serialize>(values: &[T]) -> String {
+            let mut writer = MetricsWriter::localhost();
+            let timestamps: Vec<i64> = (0..values.len() as i64).collect();
+            writer
+                .add_millis("up", &[] as &[(&str, &str)], values, &timestamps)
+                .unwrap();
+            let line = writer.payload_string().unwrap().unwrap();
+            let values = line.split_once(r#""values":"#).unwrap().1;
+            values.split_once(r#","timestamps""#).unwrap().0.to_owned()
+        }
+
+        assert_eq!(written(&[0i64, -1, i64::MAX]), "[0,-1,9223372036854775807]");
+        assert_eq!(written(&[0u64, 1, u64::MAX]), "[0,1,18446744073709551615]");
+        assert_eq!(written(&[0f32, 1.0, 0.1, -2.5]), "[0.0,1.0,0.1,-2.5]");
+        assert_eq!(written(&[0f64, 1.0, 0.1, 1e21]), "[0.0,1.0,0.1,1e21]");
+
+        let mut writer = MetricsWriter::localhost();
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(matches!(
+                writer.add_millis("up", &[("job", "a")], &[1.0, value], &[1, 2]),
+                Err(AddError::NonFiniteValue(name)) if name == "up"
+            ));
+        }
+        assert!(matches!(
+            writer.add_millis("up", &[("job", "a")], &[f32::NAN], &[1]),
+            Err(AddError::NonFiniteValue(_))
+        ));
+        assert_eq!(writer.payload_string().unwrap(), None);
+
+        // A missing optional value is written as `null` on purpose.
+        assert_eq!(written(&[Some(1.5), None]), "[1.5,null]");
+        assert!(matches!(
+            writer.add_millis("up", &[("job", "a")], &[Some(f64::NAN)], &[1]),
+            Err(AddError::NonFiniteValue(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit() {
+        let server = mock_server(204).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string()).with_rate_limit(1000);
+        let timestamps: Vec<i64> = (0..750).collect();
+
+        let start = tokio::time::Instant::now();
+        for _ in 0..2 {
+            writer
+                .add_millis("up", &[("job", "a")], &vec![1; 750], &timestamps)
+                .unwrap();
+            writer.send().await.unwrap();
+        }
+        // 1000 samples pass right away, the remaining 500 take half a second.
+        // Paused time can also run ahead to unrelated timers while waiting
+        // for the server, so only the lower bound holds; the limiter's own
+        // test checks the rate.
+        assert!(start.elapsed() >= Duration::from_millis(450));
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_samples_are_counted_when_added() {
+        let mut writer = MetricsWriter::localhost().with_rate_limit(1000);
+        writer
+            .add_millis("up", &[("job", "a")], &[1, 2], &[1, 2])
+            .unwrap();
+        writer
+            .add_json_value(&serde_json::json!({
+                "metric": {"__name__": "up", "job": "b"},
+                "values": [1, 2, 3],
+                "timestamps": [1, 2, 3],
+            }))
+            .unwrap();
+        writer
+            .buffer_writer()
+            .write_all(br#"{"metric":{"__name__":"up"},"values":[1,2,3,4],"timestamps":[1,2,3,4]}"#)
+            .await
+            .unwrap();
+        writer.buffer_writer().write_all(b"\n").await.unwrap();
+        writer
+            .add_for_tenant("1", "up", &[("job", "c")], &[1], &[Utc::now()])
+            .unwrap();
+
+        let samples = |batches: Vec<(Vec<u8>, usize)>| -> Vec<usize> {
+            batches.into_iter().map(|(_, samples)| samples).collect()
+        };
+        assert_eq!(samples(writer.buffer.take_batches()), [9]);
+        assert_eq!(
+            samples(writer.tenants.get_mut("1").unwrap().take_batches()),
+            [1]
+        );
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send<T: Send>(_: &T) {}
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<MetricsWriter>();
+        assert_send_sync::<NoopMetricsWriter>();
+        assert_send_sync::<SendError>();
+        assert_send_sync::<AddError>();
+        assert_send_sync::<ConfigError>();
+        assert_send_sync::<FlushError>();
+        assert_send_sync::<InFlightLimit>();
+        assert_send_sync::<ImportUrlConfig>();
+        assert_send_sync::<MetricData>();
+        assert_send_sync::<MetricValue>();
+        assert_send_sync::<BufferWriter>();
+
+        let sink: Box<dyn MetricsSink> = Box::new(NoopMetricsWriter);
+        assert_send(&sink);
+
+        let mut writer = MetricsWriter::localhost();
+        assert_send(&writer.export("up", None, None));
+        assert_send(&writer.send());
+        assert_send(&writer.send_with_deadline(Instant::now()));
+        assert_send(&writer.replay_file("metrics.jsonl"));
+        assert_send(&writer.add_with_flush("up", &[("job", "a")], &[1], &[], |_| true));
+    }
+
+    #[test]
+    fn test_with_host_port() {
+        for (host, port, combined) in [
+            ("localhost", 8428, "localhost:8428"),
+            ("10.0.0.1", 80, "10.0.0.1:80"),
+            ("::1", 8428, "[::1]:8428"),
+        ] {
+            assert_eq!(
+                build_import_url(&MetricsWriter::with_host_port(host, port).url_config),
+                build_import_url(&MetricsWriter::new(combined).url_config)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_reason() {
+        let server = mock_server(204).await;
+        let outcomes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut writer = MetricsWriter::new(&server.address().to_string()).with_on_flush({
+            let outcomes = outcomes.clone();
+            move |outcome| outcomes.lock().unwrap().push(*outcome)
+        });
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+
+        for job in ["a", "b"] {
+            writer
+                .add_with_flush("up", &[("job", job)], &[1], &timestamps, |stats| {
+                    stats.lines >= 2
+                })
+                .await
+                .unwrap();
+        }
+        writer
+            .add("up", &[("job", "c")], &[1], &timestamps)
+            .unwrap();
+        writer.send().await.unwrap();
+
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            [
+                SendOutcome {
+                    reason: FlushReason::Threshold,
+                    lines: 2,
+                    success: true,
+                },
+                SendOutcome {
+                    reason: FlushReason::Manual,
+                    lines: 1,
+                    success: true,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_latency_stats() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204).set_delay(Duration::from_millis(50)))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        assert_eq!(writer.stats().latency_avg, None);
+        for _ in 0..2 {
+            writer
+                .add_millis("up", &[("job", "a")], &[1], &[1549891472010])
+                .unwrap();
+            writer.send().await.unwrap();
+        }
+
+        let stats = writer.stats();
+        let (min, max, avg) = (
+            stats.latency_min.unwrap(),
+            stats.latency_max.unwrap(),
+            stats.latency_avg.unwrap(),
+        );
+        assert!(max >= Duration::from_millis(50));
+        assert!(min < Duration::from_millis(50));
+        assert!(min <= avg && avg <= max);
+    }
+
+    #[tokio::test]
+    async fn test_add_snapshot() {
+        let server = mock_server(204).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string())
+            .with_clock(|| Utc.timestamp_millis_opt(1549891500000).unwrap());
+        writer.add_snapshot("temp", &[("room", "a")], 21.5).unwrap();
+        writer.add_snapshot("temp", &[("room", "b")], 19.0).unwrap();
+        writer
+            .add_snapshot("doors_open", &[] as &[(&str, &str)], 2)
+            .unwrap();
+        assert_eq!(writer.payload(), None);
+        writer.send().await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let lines = received_lines(&requests[0]);
+        assert_eq!(lines.len(), 3);
+        for line in &lines {
+            assert_eq!(line["timestamps"], serde_json::json!([1549891500000i64]));
+        }
+        assert_eq!(lines[0]["values"], serde_json::json!([21.5]));
+        assert_eq!(lines[2]["metric"]["__name__"], "doors_open");
+    }
+
+    #[test]
+    fn test_name_normalization() {
+        let mut writer = MetricsWriter::localhost().with_name_normalization(true);
+        writer
+            .add_millis(
+                "my.metric-1",
+                &[("http.method", "GET"), ("1st", "x"), ("ok_name", "y")],
+                &[1],
+                &[1549891472010],
+            )
+            .unwrap();
+        writer
+            .add_millis("job:rate5m", &[("a:b", "c")], &[1], &[1549891472010])
+            .unwrap();
+        // Keys that only collide once normalized are duplicates.
+        assert!(matches!(
+            writer.add_millis("up", &[("a.b", "1"), ("a-b", "2")], &[1], &[1549891472010]),
+            Err(AddError::DuplicateLabel(key)) if key == "a_b"
+        ));
+        assert!(matches!(
+            writer.add_snapshot("up", &[("a.b", "1"), ("a-b", "2")], 1),
+            Err(AddError::DuplicateLabel(key)) if key == "a_b"
+        ));
+        // JSON metrics only have their name normalized.
+        writer
+            .add_json_value(&serde_json::json!({
+                "metric": {"__name__": "json.metric", "a.b": "c"},
+                "values": [1],
+                "timestamps": [1549891472010i64],
+            }))
+            .unwrap();
+
+        assert_eq!(
+            writer.payload_string().unwrap().unwrap(),
+            concat!(
+                r#"{"metric":{"__name__":"my_metric_1","http_method":"GET","_1st":"x","ok_name":"y"},"values":[1],"timestamps":[1549891472010]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"job:rate5m","a_b":"c"},"values":[1],"timestamps":[1549891472010]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"json_metric","a.b":"c"},"timestamps":[1549891472010],"values":[1]}"#,
+                "\r\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_series_per_flush() {
+        let server = mock_server(204).await;
+        let mut writer =
+            MetricsWriter::new(&server.address().to_string()).with_max_series_per_flush(2);
+        let line = |job: &str| {
+            format!(
+                r#"{{"metric":{{"__name__":"up","job":"{}"}},"values":[1],"timestamps":[1]}}"#,
+                job
+            )
+        };
+        let jobs = |requests: &[wiremock::Request]| -> Vec<Vec<String>> {
+            requests
+                .iter()
+                .map(|request| {
+                    received_lines(request)
+                        .iter()
+                        .map(|line| line["metric"]["job"].as_str().unwrap().to_owned())
+                        .collect()
+                })
+                .collect()
+        };
+        async fn add(writer: &mut MetricsWriter, job: &str, ts: i64) -> Result<bool, FlushError> {
+            let timestamps = [Utc.timestamp_millis_opt(ts).unwrap()];
+            writer
+                .add_with_flush("up", &[("job", job)], &[1], &timestamps, |_| false)
+                .await
+        }
+
+        for (job, ts) in [("a", 1), ("b", 1), ("a", 2)] {
+            assert!(!add(&mut writer, job, ts).await.unwrap());
+        }
+        assert!(server.received_requests().await.unwrap().is_empty());
+        // The third series sends the first two right away.
+        assert!(add(&mut writer, "c", 1).await.unwrap());
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(jobs(&requests), [vec!["a", "b", "a"]]);
+        assert_eq!(writer.buffer_stats().lines, 1);
+
+        // Every way of adding starts batches, and the payload covers them
+        // all.
+        writer
+            .add_millis("up", &[("job", "d")], &[1], &[1])
+            .unwrap();
+        writer
+            .add_json_value(&serde_json::from_str(&line("e")).unwrap())
+            .unwrap();
+        writer
+            .buffer_writer()
+            .write_all(format!("{}\n{}\n", line("f"), line("g")).as_bytes())
+            .await
+            .unwrap();
+        let payload = writer.payload_string().unwrap().unwrap();
+        assert_eq!(payload.lines().count(), 5);
+        assert_eq!(
+            writer.payload_checksum(),
+            Some({
+                let mut crc = flate2::Crc::new();
+                crc.update(payload.as_bytes());
+                crc.sum()
+            })
+        );
+
+        // Draining takes lines from the oldest batch first.
+        let drained = writer.drain_up_to(1).unwrap();
+        assert!(std::str::from_utf8(&drained)
+            .unwrap()
+            .contains(r#""job":"c""#));
+        writer.send().await.unwrap();
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(jobs(&requests[1..]), [vec!["d"], vec!["e", "f"], vec!["g"]]);
+    }
+
+    #[tokio::test]
+    async fn test_send_returning() {
+        let server = mock_server(204).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string());
+        assert_eq!(writer.send_returning().await.unwrap(), None);
+
+        writer
+            .add_millis("up", &[("job", "api")], &[1, 0], &[1000, 2000])
+            .unwrap();
+        let delivered = writer.send_returning().await.unwrap().unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(delivered, requests[0].body);
+        assert_eq!(writer.payload(), None);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_is_honored() {
+        // The server's delay wins over the backoff, but only up to the
+        // maximum delay.
+        for (retry_after, max_delay, expected) in [
+            (
+                "86400",
+                Duration::from_millis(100),
+                Duration::from_millis(100),
+            ),
+            ("1", Duration::from_secs(5), Duration::from_secs(1)),
+        ] {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", retry_after))
+                .up_to_n_times(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(204))
+                .mount(&server)
+                .await;
+
+            let mut writer = MetricsWriter::new(&server.address().to_string()).with_retries(
+                3,
+                Duration::from_millis(1),
+                max_delay,
+            );
+            writer
+                .add_millis("up", &[("job", "api")], &[1], &[1000])
+                .unwrap();
+            let started = Instant::now();
+            writer.send().await.unwrap();
+            let elapsed = started.elapsed();
+
+            assert_eq!(server.received_requests().await.unwrap().len(), 2);
+            assert!(elapsed >= expected, "{:?}", elapsed);
+            assert!(
+                elapsed < max_delay + Duration::from_secs(5),
+                "{:?}",
+                elapsed
+            );
+            assert_eq!(writer.stats().requests, 2);
+            assert_eq!(writer.stats().failures, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unbuildable_request_is_not_retried() {
+        let server = mock_server(204).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string())
+            .with_bearer_auth("not\na header value")
+            .with_retries(3, Duration::from_secs(10), Duration::from_secs(10));
+        writer
+            .add_millis("up", &[("job", "api")], &[1], &[1000])
+            .unwrap();
+        let started = Instant::now();
+        assert!(matches!(
+            writer.send().await,
+            Err(SendError::RequestError(err)) if err.is_builder()
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(server.received_requests().await.unwrap().is_empty());
+        assert_eq!(writer.stats().requests, 0);
+        assert_eq!(writer.stats().failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_without_retry_after_uses_backoff() {
+        let server = mock_server(429).await;
+        let mut writer = MetricsWriter::new(&server.address().to_string()).with_retries(
+            2,
+            Duration::from_millis(10),
+            Duration::from_secs(1),
+        );
+        writer
+            .add_millis("up", &[("job", "api")], &[1], &[1000])
+            .unwrap();
+        let err = writer.send().await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            SendError::InvalidResponseStatusCode(StatusCode::TOO_MANY_REQUESTS, _)
+        ));
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+        assert_eq!(writer.stats().failures, 1);
     }
 }
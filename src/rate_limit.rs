@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// A token bucket holding up to one second's worth of samples. Requests
+/// larger than the bucket are let through after waiting off the debt, so the
+/// rate holds on average.
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimiter {
+    samples_per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(samples_per_second: u32, now: Instant) -> Self {
+        let samples_per_second = f64::from(samples_per_second.max(1));
+        RateLimiter {
+            samples_per_second,
+            tokens: samples_per_second,
+            updated: now,
+        }
+    }
+
+    /// Takes `samples` tokens at `now`, returning how long to wait before
+    /// sending them.
+    pub(crate) fn reserve(&mut self, samples: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.samples_per_second).min(self.samples_per_second);
+        self.updated = now;
+        self.tokens -= samples as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.samples_per_second)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_is_kept() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(100, start);
+
+        // The first second's worth passes right away.
+        assert_eq!(limiter.reserve(100, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(50, start), Duration::from_millis(500));
+
+        // Sending 50 samples whenever allowed admits no more than
+        // 100 + 100/s over any stretch.
+        let mut now = start + Duration::from_millis(500);
+        let mut sent = 150;
+        while now < start + Duration::from_secs(10) {
+            now += limiter.reserve(50, now);
+            sent += 50;
+            let allowed = 100.0 + 100.0 * (now - start).as_secs_f64();
+            assert!(sent as f64 <= allowed + 50.0, "{} > {}", sent, allowed);
+        }
+        assert!(sent >= 1000);
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use metrics::{
+    Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+};
+use tokio::task::JoinHandle;
+
+use crate::{AddError, FlushReason, FlushSchedule, Labels, MetricsWriter};
+
+/// The bucket bounds histograms are recorded in unless configured with
+/// [`VictoriaMetricsRecorder::with_buckets`]; the Prometheus client default.
+pub const DEFAULT_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A [`metrics::Recorder`] keeping the current value of every counter,
+/// gauge and histogram recorded through the `metrics` facade, to be written
+/// to a [`MetricsWriter`] with [`VictoriaMetricsRecorder::write_to`] or
+/// periodically with [`VictoriaMetricsRecorder::spawn_flusher`].
+///
+/// Clones share the same values, so one clone can be installed with
+/// `metrics::set_global_recorder` and another kept for flushing.
+#[derive(Clone)]
+pub struct VictoriaMetricsRecorder {
+    registry: Arc<Registry>,
+}
+
+#[derive(Default)]
+struct Registry {
+    buckets: Vec<f64>,
+    counters: Mutex<BTreeMap<Key, Arc<AtomicU64>>>,
+    gauges: Mutex<BTreeMap<Key, Arc<AtomicU64>>>,
+    histograms: Mutex<BTreeMap<Key, Arc<HistogramState>>>,
+}
+
+/// Cumulative since registration, like a Prometheus histogram.
+struct HistogramState {
+    bounds: Vec<f64>,
+    inner: Mutex<HistogramCounts>,
+}
+
+struct HistogramCounts {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl HistogramFn for HistogramState {
+    fn record(&self, value: f64) {
+        let mut counts = self.inner.lock().unwrap();
+        for (bound, bucket) in self.bounds.iter().zip(&mut counts.buckets) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        counts.sum += value;
+        counts.count += 1;
+    }
+}
+
+/// The labels of a `metrics` key.
+struct KeyLabels<'a>(&'a Key);
+
+impl Labels for KeyLabels<'_> {
+    fn for_each_label(&self, f: &mut dyn FnMut(&str, &str)) {
+        for label in self.0.labels() {
+            f(label.key(), label.value());
+        }
+    }
+}
+
+impl VictoriaMetricsRecorder {
+    pub fn new() -> Self {
+        Self::with_buckets(&DEFAULT_BUCKETS)
+    }
+
+    /// Records histograms in buckets with the given upper bounds, which
+    /// should be sorted. A `+Inf` bucket is always added.
+    pub fn with_buckets(buckets: &[f64]) -> Self {
+        VictoriaMetricsRecorder {
+            registry: Arc::new(Registry {
+                buckets: buckets.to_vec(),
+                ..Registry::default()
+            }),
+        }
+    }
+
+    /// Adds the current value of every registered metric to `writer`, all
+    /// with the same timestamp from the writer's clock. Histograms are
+    /// written as `_bucket`, `_sum` and `_count` series.
+    ///
+    /// A metric the writer rejects, e.g. a NaN gauge, is skipped and the
+    /// rest are still written; a rejected histogram leaves none of its
+    /// series behind. The first such error is returned afterwards.
+    pub fn write_to(&self, writer: &mut MetricsWriter) -> Result<(), AddError> {
+        let now = [(writer.clock)()];
+        let registry = &self.registry;
+        let mut result = Ok(());
+        let mut keep_first = |added: Result<(), AddError>| {
+            if result.is_ok() {
+                result = added;
+            }
+        };
+
+        for (key, counter) in registry.counters.lock().unwrap().iter() {
+            let value = counter.load(Ordering::Relaxed);
+            keep_first(writer.add(key.name(), &KeyLabels(key), &[value], &now));
+        }
+        for (key, gauge) in registry.gauges.lock().unwrap().iter() {
+            let value = f64::from_bits(gauge.load(Ordering::Relaxed));
+            keep_first(writer.add(key.name(), &KeyLabels(key), &[value], &now));
+        }
+        for (key, histogram) in registry.histograms.lock().unwrap().iter() {
+            let counts = histogram.inner.lock().unwrap();
+            let buckets: Vec<(f64, u64)> = histogram
+                .bounds
+                .iter()
+                .copied()
+                .zip(counts.buckets.iter().copied())
+                .collect();
+            keep_first(writer.add_histogram(
+                key.name(),
+                &KeyLabels(key),
+                &buckets,
+                counts.sum,
+                counts.count,
+                now[0],
+            ));
+        }
+        result
+    }
+
+    /// Spawns a task that writes the recorded values to `writer` and sends
+    /// it on `schedule`, like [`crate::spawn_flusher`]. Metrics that cannot
+    /// be written are left out of the send, see
+    /// [`VictoriaMetricsRecorder::write_to`].
+    pub fn spawn_flusher(
+        &self,
+        writer: Arc<tokio::sync::Mutex<MetricsWriter>>,
+        schedule: FlushSchedule,
+    ) -> JoinHandle<()> {
+        let recorder = self.clone();
+        tokio::spawn(schedule.run(move || {
+            let writer = writer.clone();
+            let recorder = recorder.clone();
+            async move {
+                let mut writer = writer.lock().await;
+                let _ = recorder.write_to(&mut writer);
+                let _ = writer.send_with_reason(FlushReason::Timer).await;
+            }
+        }))
+    }
+}
+
+impl Default for VictoriaMetricsRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recorder for VictoriaMetricsRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let mut counters = self.registry.counters.lock().unwrap();
+        Counter::from_arc(counters.entry(key.clone()).or_default().clone())
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let mut gauges = self.registry.gauges.lock().unwrap();
+        Gauge::from_arc(gauges.entry(key.clone()).or_default().clone())
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let mut histograms = self.registry.histograms.lock().unwrap();
+        let bounds = &self.registry.buckets;
+        let state = histograms.entry(key.clone()).or_insert_with(|| {
+            Arc::new(HistogramState {
+                bounds: bounds.clone(),
+                inner: Mutex::new(HistogramCounts {
+                    buckets: vec![0; bounds.len()],
+                    sum: 0.0,
+                    count: 0,
+                }),
+            })
+        });
+        Histogram::from_arc(state.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    #[test]
+    fn test_recorder() {
+        let recorder = VictoriaMetricsRecorder::with_buckets(&[0.1, 1.0]);
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("requests_total", "job" => "api").increment(3);
+            metrics::counter!("requests_total", "job" => "api").increment(2);
+            metrics::gauge!("temperature").set(21.5);
+            let latency = metrics::histogram!("latency_seconds");
+            latency.record(0.05);
+            latency.record(0.5);
+            latency.record(2.0);
+        });
+
+        let mut writer = MetricsWriter::localhost()
+            .with_clock(|| Utc.timestamp_millis_opt(1549891472010).unwrap());
+        recorder.write_to(&mut writer).unwrap();
+
+        let lines: Vec<_> = writer
+            .payload_string()
+            .unwrap()
+            .unwrap()
+            .lines()
+            .map(|line| line.replace(r#","timestamps":[1549891472010]}"#, "}"))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"metric":{"__name__":"requests_total","job":"api"},"values":[5]}"#,
+                r#"{"metric":{"__name__":"temperature"},"values":[21.5]}"#,
+                r#"{"metric":{"__name__":"latency_seconds_bucket","le":"0.1"},"values":[1]}"#,
+                r#"{"metric":{"__name__":"latency_seconds_bucket","le":"1"},"values":[2]}"#,
+                r#"{"metric":{"__name__":"latency_seconds_bucket","le":"+Inf"},"values":[3]}"#,
+                r#"{"metric":{"__name__":"latency_seconds_sum"},"values":[2.55]}"#,
+                r#"{"metric":{"__name__":"latency_seconds_count"},"values":[3]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejected_metric_is_skipped() {
+        let recorder = VictoriaMetricsRecorder::new();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("requests_total").increment(1);
+            metrics::gauge!("ratio").set(f64::NAN);
+            metrics::gauge!("temperature").set(21.5);
+        });
+
+        let mut writer = MetricsWriter::localhost();
+        assert!(matches!(
+            recorder.write_to(&mut writer),
+            Err(AddError::NonFiniteValue(name)) if name == "ratio"
+        ));
+        let payload = writer.payload_string().unwrap().unwrap();
+        assert_eq!(payload.lines().count(), 2);
+        assert!(payload.contains("requests_total") && payload.contains("temperature"));
+    }
+
+    #[test]
+    fn test_rejected_histogram_leaves_no_lines() {
+        let recorder = VictoriaMetricsRecorder::with_buckets(&[1.0]);
+        metrics::with_local_recorder(&recorder, || {
+            let latency = metrics::histogram!("latency_seconds");
+            latency.record(0.5);
+            latency.record(f64::NAN);
+            metrics::gauge!("temperature").set(21.5);
+        });
+
+        let mut writer = MetricsWriter::localhost();
+        assert!(matches!(
+            recorder.write_to(&mut writer),
+            Err(AddError::NonFiniteValue(name)) if name == "latency_seconds_sum"
+        ));
+        let payload = writer.payload_string().unwrap().unwrap();
+        assert_eq!(payload.lines().count(), 1);
+        assert!(payload.contains("temperature"));
+    }
+}
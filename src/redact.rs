@@ -0,0 +1,91 @@
+use std::collections::BTreeSet;
+
+/// How many distinct values are remembered between sends. Beyond that,
+/// messages are hidden entirely rather than risk showing a value that was
+/// not remembered.
+pub(crate) const MAX_REDACTED_VALUES: usize = 1024;
+
+/// The values of redacted labels buffered since the last send, see
+/// [`MetricsWriter::redact_label`](crate::MetricsWriter::redact_label).
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RedactedValues {
+    values: BTreeSet<String>,
+    overflowed: bool,
+}
+
+impl RedactedValues {
+    pub(crate) fn insert(&mut self, value: &str) {
+        if value.is_empty() || self.values.contains(value) {
+            return;
+        }
+        if self.values.len() >= MAX_REDACTED_VALUES {
+            self.overflowed = true;
+        } else {
+            self.values.insert(value.to_owned());
+        }
+    }
+
+    /// Replaces every remembered value in `message` with `***` where it
+    /// appears as a whole token, so that e.g. a value `1` leaves `400`
+    /// alone.
+    pub(crate) fn redact(&self, message: &str) -> String {
+        if self.overflowed {
+            return "***".to_owned();
+        }
+        let mut values: Vec<&String> = self.values.iter().collect();
+        // Replace longer values first so a value containing another one is
+        // not left partially visible.
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        values
+            .into_iter()
+            .fold(message.to_owned(), |message, value| {
+                replace_token(&message, value)
+            })
+    }
+}
+
+fn replace_token(message: &str, value: &str) -> String {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut redacted = String::with_capacity(message.len());
+    let mut rest = 0;
+    for (start, _) in message.match_indices(value) {
+        let end = start + value.len();
+        let before = message[..start].chars().next_back();
+        let after = message[end..].chars().next();
+        if before.is_some_and(is_word) || after.is_some_and(is_word) {
+            continue;
+        }
+        redacted.push_str(&message[rest..start]);
+        redacted.push_str("***");
+        rest = end;
+    }
+    redacted.push_str(&message[rest..]);
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_whole_tokens_only() {
+        let mut values = RedactedValues::default();
+        values.insert("1");
+        values.insert("a");
+        values.insert("s3cr3t");
+        assert_eq!(
+            values.redact(r#"status 401: bad line {"job":"a","user":"1","token":"s3cr3t"}"#),
+            r#"status 401: bad line {"job":"***","user":"***","token":"***"}"#
+        );
+    }
+
+    #[test]
+    fn test_overflow_hides_message() {
+        let mut values = RedactedValues::default();
+        for i in 0..=MAX_REDACTED_VALUES {
+            values.insert(&format!("user-{}", i));
+        }
+        assert_eq!(values.values.len(), MAX_REDACTED_VALUES);
+        assert_eq!(values.redact("cannot parse user-0"), "***");
+    }
+}
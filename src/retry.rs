@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+
+/// Retries failed import requests, doubling the delay after each attempt
+/// unless the server asks for a specific one, and never waiting longer than
+/// `max_delay`.
+#[derive(Clone, Debug)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: u32,
+    backoff: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub(crate) fn new(max_retries: u32, backoff: Duration, max_delay: Duration) -> Self {
+        RetryPolicy {
+            max_retries,
+            backoff,
+            max_delay,
+        }
+    }
+
+    /// Returns how long to wait before retry number `attempt`, counting from
+    /// zero, preferring the server's `retry_after` over the backoff.
+    pub(crate) fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        retry_after
+            .unwrap_or_else(|| {
+                self.backoff
+                    .saturating_mul(2u32.saturating_pow(attempt.min(16)))
+            })
+            .min(self.max_delay)
+    }
+}
+
+/// Returns whether a request that failed with `status` is worth retrying.
+pub(crate) fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Parses a `Retry-After` header value, given either as seconds or as an
+/// HTTP date, into the delay left at `now`.
+pub(crate) fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::new(3, Duration::from_millis(100), Duration::from_secs(2));
+        assert_eq!(policy.delay(0, None), Duration::from_millis(100));
+        assert_eq!(policy.delay(2, None), Duration::from_millis(400));
+        assert_eq!(policy.delay(5, None), Duration::from_secs(2));
+        assert_eq!(
+            policy.delay(2, Some(Duration::from_secs(1))),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(86400))),
+            Duration::from_secs(2)
+        );
+    }
+}
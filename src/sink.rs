@@ -0,0 +1,109 @@
+use std::{future::Future, pin::Pin};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{AddError, Labels, SendError, WriteMetrics};
+
+/// A sample value for [`MetricsSink`], which cannot be generic over the value
+/// type.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum MetricValue {
+    Integer(i64),
+    Unsigned(u64),
+    Float(f64),
+}
+
+impl From<i64> for MetricValue {
+    fn from(value: i64) -> Self {
+        MetricValue::Integer(value)
+    }
+}
+
+impl From<u64> for MetricValue {
+    fn from(value: u64) -> Self {
+        MetricValue::Unsigned(value)
+    }
+}
+
+impl From<f64> for MetricValue {
+    fn from(value: f64) -> Self {
+        MetricValue::Float(value)
+    }
+}
+
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<(), SendError>> + Send + 'a>>;
+
+/// An object-safe version of [`WriteMetrics`], for storing writers as
+/// `Box<dyn MetricsSink>`. Implemented for every [`WriteMetrics`] type.
+pub trait MetricsSink: Send {
+    fn add(
+        &mut self,
+        name: &str,
+        labels: &dyn Labels,
+        values: &[MetricValue],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<(), AddError>;
+
+    fn send(&mut self) -> SendFuture<'_>;
+}
+
+impl<W: WriteMetrics + Send> MetricsSink for W {
+    fn add(
+        &mut self,
+        name: &str,
+        labels: &dyn Labels,
+        values: &[MetricValue],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<(), AddError> {
+        WriteMetrics::add(self, name, labels, values, timestamps)
+    }
+
+    fn send(&mut self) -> SendFuture<'_> {
+        Box::pin(WriteMetrics::send(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::{MetricsWriter, NoopMetricsWriter};
+
+    #[tokio::test]
+    async fn test_dyn_sink() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut sinks: Vec<Box<dyn MetricsSink>> = vec![
+            Box::new(MetricsWriter::new(&server.address().to_string())),
+            Box::new(NoopMetricsWriter),
+        ];
+        let timestamps = [Utc.timestamp_millis_opt(1549891472010).unwrap()];
+        for sink in &mut sinks {
+            sink.add("up", &[("job", "a")], &[1i64.into()], &timestamps)
+                .unwrap();
+            sink.add("load", &[("job", "a")], &[0.5.into()], &timestamps)
+                .unwrap();
+            sink.send().await.unwrap();
+        }
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&requests[0].body).unwrap(),
+            concat!(
+                r#"{"metric":{"__name__":"up","job":"a"},"values":[1],"timestamps":[1549891472010]}"#,
+                "\r\n",
+                r#"{"metric":{"__name__":"load","job":"a"},"values":[0.5],"timestamps":[1549891472010]}"#,
+                "\r\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,339 @@
+use std::fmt;
+
+use serde::ser::{self, Serialize};
+
+use crate::ValueKind;
+
+/// Why a value has no [`ValueKind`], see [`value_kind`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum KindError {
+    /// The value is or contains a NaN or infinite float, which `serde_json`
+    /// writes as `null`.
+    NonFinite,
+    /// The value's `Serialize` implementation failed.
+    Custom(String),
+}
+
+impl fmt::Display for KindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KindError::NonFinite => f.write_str("non-finite float"),
+            KindError::Custom(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for KindError {}
+
+impl ser::Error for KindError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        KindError::Custom(msg.to_string())
+    }
+}
+
+/// Returns whether `value` is written as a JSON integer or float, or `None`
+/// for anything else, e.g. `None::<f64>`. Runs the value's `Serialize`
+/// implementation without writing anything, so that checking values on
+/// every add does not allocate.
+pub(crate) fn value_kind<T: Serialize + ?Sized>(value: &T) -> Result<Option<ValueKind>, KindError> {
+    value.serialize(KindSerializer)
+}
+
+struct KindSerializer;
+
+/// Checks the elements of a sequence, map or struct for non-finite floats.
+struct Elements;
+
+fn float(value: f64) -> Result<Option<ValueKind>, KindError> {
+    if value.is_finite() {
+        Ok(Some(ValueKind::Float))
+    } else {
+        Err(KindError::NonFinite)
+    }
+}
+
+impl ser::Serializer for KindSerializer {
+    type Ok = Option<ValueKind>;
+    type Error = KindError;
+    type SerializeSeq = Elements;
+    type SerializeTuple = Elements;
+    type SerializeTupleStruct = Elements;
+    type SerializeTupleVariant = Elements;
+    type SerializeMap = Elements;
+    type SerializeStruct = Elements;
+    type SerializeStructVariant = Elements;
+
+    fn serialize_bool(self, _: bool) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+
+    fn serialize_i8(self, _: i8) -> Result<Self::Ok, KindError> {
+        Ok(Some(ValueKind::Integer))
+    }
+
+    fn serialize_i16(self, _: i16) -> Result<Self::Ok, KindError> {
+        Ok(Some(ValueKind::Integer))
+    }
+
+    fn serialize_i32(self, _: i32) -> Result<Self::Ok, KindError> {
+        Ok(Some(ValueKind::Integer))
+    }
+
+    fn serialize_i64(self, _: i64) -> Result<Self::Ok, KindError> {
+        Ok(Some(ValueKind::Integer))
+    }
+
+    fn serialize_i128(self, _: i128) -> Result<Self::Ok, KindError> {
+        Ok(Some(ValueKind::Integer))
+    }
+
+    fn serialize_u8(self, _: u8) -> Result<Self::Ok, KindError> {
+        Ok(Some(ValueKind::Integer))
+    }
+
+    fn serialize_u16(self, _: u16) -> Result<Self::Ok, KindError> {
+        Ok(Some(ValueKind::Integer))
+    }
+
+    fn serialize_u32(self, _: u32) -> Result<Self::Ok, KindError> {
+        Ok(Some(ValueKind::Integer))
+    }
+
+    fn serialize_u64(self, _: u64) -> Result<Self::Ok, KindError> {
+        Ok(Some(ValueKind::Integer))
+    }
+
+    fn serialize_u128(self, _: u128) -> Result<Self::Ok, KindError> {
+        Ok(Some(ValueKind::Integer))
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<Self::Ok, KindError> {
+        float(value.into())
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<Self::Ok, KindError> {
+        float(value)
+    }
+
+    fn serialize_char(self, _: char) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+
+    fn serialize_str(self, _: &str) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+
+    fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, KindError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, KindError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, KindError> {
+        value.serialize(self).map(|_| None)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Elements, KindError> {
+        Ok(Elements)
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Elements, KindError> {
+        Ok(Elements)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Elements, KindError> {
+        Ok(Elements)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Elements, KindError> {
+        Ok(Elements)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Elements, KindError> {
+        Ok(Elements)
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Elements, KindError> {
+        Ok(Elements)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Elements, KindError> {
+        Ok(Elements)
+    }
+}
+
+impl Elements {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), KindError> {
+        value.serialize(KindSerializer).map(|_| ())
+    }
+}
+
+impl ser::SerializeSeq for Elements {
+    type Ok = Option<ValueKind>;
+    type Error = KindError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), KindError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+}
+
+impl ser::SerializeTuple for Elements {
+    type Ok = Option<ValueKind>;
+    type Error = KindError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), KindError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+}
+
+impl ser::SerializeTupleStruct for Elements {
+    type Ok = Option<ValueKind>;
+    type Error = KindError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), KindError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+}
+
+impl ser::SerializeTupleVariant for Elements {
+    type Ok = Option<ValueKind>;
+    type Error = KindError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), KindError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+}
+
+impl ser::SerializeMap for Elements {
+    type Ok = Option<ValueKind>;
+    type Error = KindError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), KindError> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), KindError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+}
+
+impl ser::SerializeStruct for Elements {
+    type Ok = Option<ValueKind>;
+    type Error = KindError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), KindError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+}
+
+impl ser::SerializeStructVariant for Elements {
+    type Ok = Option<ValueKind>;
+    type Error = KindError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), KindError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, KindError> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_kind() {
+        assert_eq!(value_kind(&1u8), Ok(Some(ValueKind::Integer)));
+        assert_eq!(value_kind(&-1i64), Ok(Some(ValueKind::Integer)));
+        assert_eq!(value_kind(&1.5f32), Ok(Some(ValueKind::Float)));
+        assert_eq!(value_kind(&Some(1.5)), Ok(Some(ValueKind::Float)));
+        assert_eq!(value_kind(&None::<f64>), Ok(None));
+        assert_eq!(value_kind(&"1"), Ok(None));
+        assert_eq!(value_kind(&f64::NAN), Err(KindError::NonFinite));
+        assert_eq!(value_kind(&Some(f32::INFINITY)), Err(KindError::NonFinite));
+        assert_eq!(value_kind(&[1.0, f64::NAN]), Err(KindError::NonFinite));
+    }
+}